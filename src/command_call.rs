@@ -1,10 +1,17 @@
-use std::io::BufRead;
+use std::io::Write;
 use std::net::{SocketAddr, UdpSocket};
 use std::time::Duration;
 
 const MAX_UDP_PACKET: usize = 65507;
 
-pub fn try_run(args: &mut noargs::RawArgs) -> noargs::Result<bool> {
+// Maximum time blocked in a single receive, so that Ctrl-C is noticed promptly
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const DEFAULT_SEND_BUF_SIZE: usize = 1200;
+pub(crate) const IPV4_HEADER_SIZE: usize = 20;
+pub(crate) const IPV6_HEADER_SIZE: usize = 40;
+pub(crate) const UDP_HEADER_SIZE: usize = 8;
+
+pub fn try_run(args: &mut noargs::RawArgs, verbosity: crate::Verbosity) -> noargs::Result<bool> {
     if !noargs::cmd("call")
         .doc("Read JSON-RPC requests from standard input and execute the RPC calls")
         .take(args)
@@ -13,158 +20,2568 @@ pub fn try_run(args: &mut noargs::RawArgs) -> noargs::Result<bool> {
         return Ok(false);
     }
 
-    let server_addr: SocketAddr = noargs::arg("<SERVER>")
-        .doc("JSON-RPC server address or hostname")
-        .example("127.0.0.1:8080")
-        .take(args)
-        .then(|a| crate::utils::parse_socket_addr(a.value()))?;
     let pretty: bool = noargs::flag("pretty")
         .short('p')
         .doc("Pretty-print JSON responses to stdout")
         .take(args)
         .is_present();
-    let send_buf_size: std::num::NonZeroUsize = noargs::opt("send-buf-size")
+    let ndjson_out: bool = noargs::flag("ndjson-out")
+        .doc("Reserialize each response compactly so that it always fits on a single line")
+        .take(args)
+        .is_present();
+    let sort_keys: bool = noargs::flag("sort-keys")
+        .doc("Sort the members of JSON objects in responses by name (for stable diffs)")
+        .take(args)
+        .is_present();
+    let table: bool = noargs::flag("table")
+        .doc("Print responses as an aligned text table of id, status and (truncated) value")
+        .take(args)
+        .is_present();
+    let table_width: usize = noargs::opt("table-width")
+        .ty("CHARS")
+        .doc("Max width of the value column of --table")
+        .default("60")
+        .take(args)
+        .then(|o| o.value().parse())?;
+    let compact_errors: bool = noargs::flag("compact-errors")
+        .doc("Print error responses as just {\"id\",\"code\",\"message\"} (dropping \"data\")")
+        .take(args)
+        .is_present();
+    let strip_id: bool = noargs::flag("strip-id")
+        .doc("Remove the \"id\" member from each response before printing it")
+        .take(args)
+        .is_present();
+    let truncate_value: Option<usize> = noargs::opt("truncate-value")
+        .ty("CHARS")
+        .doc("Shorten string values longer than CHARS characters in responses to CHARS characters and \"…\"")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let prefix: Option<String> = noargs::opt("prefix")
+        .ty("STRING")
+        .doc("Prepend STRING and a tab to each printed response (e.g., to tag outputs of multiple servers)")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let send_buf_size: Option<std::num::NonZeroUsize> = noargs::opt("send-buf-size")
         .short('b')
+        .ty("BYTES | auto")
+        .doc(concat!(
+            "Max UDP payload per outgoing packet; requests are packed up to this size\n",
+            "(`auto` derives it from --mtu if specified, and uses 1200 otherwise)"
+        ))
+        .default("auto")
+        .take(args)
+        .then(|o| match o.value() {
+            "auto" => Ok(None),
+            value => value.parse().map(Some),
+        })?;
+    let mtu: Option<usize> = noargs::opt("mtu")
+        .ty("BYTES")
+        .doc(concat!(
+            "Link MTU used by `--send-buf-size auto`\n",
+            "(assumes 20 / 40 bytes of IPv4 / IPv6 headers without options, plus 8 bytes of UDP header)"
+        ))
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let warn_mtu: usize = noargs::opt("warn-mtu")
+        .ty("BYTES")
+        .doc(concat!(
+            "Warn if an outgoing packet (including IP and UDP headers) exceeds BYTES and may be fragmented\n",
+            "(default: the --mtu value if specified, otherwise 1500)"
+        ))
+        .take(args)
+        .present_and_then(|o| o.value().parse())?
+        .or(mtu)
+        .unwrap_or(1500);
+    let framing: crate::framing::Framing = noargs::opt("framing")
+        .ty("lines | content-length")
+        .doc("How multiple messages are delimited within a UDP packet or the --exec pipes")
+        .default("lines")
+        .take(args)
+        .then(|o| o.value().parse())?;
+    let flush_every: usize = noargs::opt("flush-every")
+        .ty("COUNT")
+        .doc("Flush the send buffer after every COUNT requests (0 means only when full)")
+        .default("0")
+        .take(args)
+        .then(|o| o.value().parse())?;
+    let timeout: Option<Duration> = noargs::opt("timeout")
+        .ty("SECONDS")
+        .doc(concat!(
+            "Idle timeout for waiting responses, reset whenever a response arrives (default: 5)\n",
+            "(a request may instead set its own response deadline with a nonstandard \"_timeout_ms\" member,\n",
+            "which is removed before sending)"
+        ))
+        .take(args)
+        .present_and_then(|o| crate::utils::parse_duration_secs(o.value()))?;
+    let block: bool = noargs::flag("block")
+        .doc("Wait for responses forever instead of timing out (--timeout 0 is accepted only with this)")
+        .take(args)
+        .is_present();
+    let deadline: Option<Duration> = noargs::opt("deadline")
+        .ty("SECONDS")
+        .doc("Maximum total time for waiting responses, regardless of --timeout")
+        .take(args)
+        .present_and_then(|o| crate::utils::parse_duration_secs(o.value()))?;
+    let input_format: crate::input::InputFormat = noargs::opt("format")
+        .ty("auto | ndjson | json | msgpack")
+        .doc(concat!(
+            "Format of the requests read from stdin\n",
+            "(`json` accepts pretty-printed objects and arrays of requests; `auto` detects it from the first line;\n",
+            "`msgpack` converts concatenated MessagePack values to JSON and requires the `msgpack` cargo feature)"
+        ))
+        .default("auto")
+        .take(args)
+        .then(|o| o.value().parse())?;
+    let output_format: OutputFormat = noargs::opt("output-format")
+        .ty("json | msgpack")
+        .doc("Encoding of the responses written to stdout (`msgpack` requires the `msgpack` cargo feature)")
+        .default("json")
+        .take(args)
+        .then(|o| o.value().parse())?;
+    let max_response_size: Option<usize> = noargs::opt("max-response-size")
+        .ty("BYTES")
+        .doc("Abort if a single response exceeds BYTES (default: no limit other than the UDP packet size)")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let expect: Option<usize> = noargs::opt("expect")
+        .ty("COUNT")
+        .doc("Wait for exactly COUNT responses instead of one per non-notification request")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let summary: bool = noargs::flag("summary")
+        .doc("Print a JSON summary (counts, elapsed time and transferred bytes) to stderr on exit")
+        .take(args)
+        .is_present();
+    let assertions: Vec<Assertion> = crate::utils::take_repeated_opt(
+        noargs::opt("assert")
+            .ty("POINTER=VALUE")
+            .doc(concat!(
+                "Fail if the JSON Pointer POINTER of a response does not equal VALUE (repeatable)\n",
+                "(VALUE is parsed as JSON; if that fails, it is treated as a string)"
+            ))
+            .example("/result/status=ok"),
+        args,
+        |o| o.value().parse(),
+    )?;
+    let strict_responses: bool = noargs::flag("strict-responses")
+        .doc(concat!(
+            "Also reject responses with a null \"result\" or an \"error\" other than {\"code\":INTEGER,\"message\":STRING}\n",
+            "(responses with neither or both of them are always rejected; offending ids are reported on stderr)"
+        ))
+        .take(args)
+        .is_present();
+    let abort_on_first_error: bool = noargs::flag("abort-on-first-error")
+        .doc(
+            "Stop and fail as soon as an error response arrives, reporting the unanswered requests",
+        )
+        .take(args)
+        .is_present();
+    let filter: Option<ResponseFilter> = noargs::opt("filter")
+        .ty("POINTER[=VALUE | =present]")
+        .doc(concat!(
+            "Print only responses having a value at the JSON Pointer POINTER\n",
+            "(if VALUE is given, the value must also equal it)"
+        ))
+        .example("/error")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let quiet: bool = noargs::flag("quiet")
+        .short('q')
+        .doc("Print only the number of (matching) responses instead of the responses themselves")
+        .take(args)
+        .is_present();
+    let dedup: bool = noargs::flag("dedup")
+        .doc("Skip input requests that are byte-identical to an earlier one")
+        .take(args)
+        .is_present();
+    let overrides: Vec<RequestOverride> = crate::utils::take_repeated_opt(
+        noargs::opt("set")
+            .ty("POINTER=VALUE")
+            .doc(concat!(
+                "Set the JSON Pointer POINTER of each request to VALUE before sending it (repeatable)\n",
+                "(missing objects are created; VALUE is parsed as JSON, or treated as a string if that fails)"
+            ))
+            .example("/params/token=abc123"),
+        args,
+        |o| o.value().parse(),
+    )?;
+    let only_ids: Vec<nojson::RawJsonOwned> = crate::utils::take_repeated_opt(
+        noargs::opt("only-id").ty("ID").doc(concat!(
+            "Only send input requests whose id equals ID, skipping the others (repeatable)\n",
+            "(ID is parsed as JSON; if that fails, it is treated as a string)"
+        )),
+        args,
+        |o| parse_expected_value(o.value()),
+    )?;
+    let method_case: crate::utils::MethodCase = noargs::opt("method-case")
+        .ty("asis | lower | upper")
+        .doc("Convert the method names of the requests to lowercase or uppercase before sending them")
+        .default("asis")
+        .take(args)
+        .then(|o| o.value().parse())?;
+    let max_line_length: usize = noargs::opt("max-line-length")
         .ty("BYTES")
-        .doc("Max UDP payload per outgoing packet; requests are joined with '\\n' up to this size")
-        .default("1200")
+        .doc("Abort if an input line exceeds BYTES (each line of a pretty-printed --format json document counts separately)")
+        .default("4194304")
+        .take(args)
+        .then(|o| o.value().parse())?;
+    let accept_version: crate::utils::AcceptVersion = noargs::opt("accept-version")
+        .ty("2.0 | 1.0 | any")
+        .doc(concat!(
+            "JSON-RPC versions accepted in the \"jsonrpc\" member of input requests\n",
+            "(`1.0` also accepts requests without the member)"
+        ))
+        .default("2.0")
+        .take(args)
+        .then(|o| o.value().parse())?;
+    let allow_comments: bool = noargs::flag("allow-comments")
+        .doc("Skip input lines starting with '#' or '//' (blank lines are always skipped)")
+        .take(args)
+        .is_present();
+    let method: Option<String> = noargs::opt("method")
+        .short('m')
+        .ty("NAME")
+        .doc("Send requests calling NAME instead of reading them from stdin")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let params: Option<nojson::RawJsonOwned> = noargs::opt("params")
+        .ty("OBJECT | ARRAY")
+        .doc("Parameters of the requests generated by --method (`@PATH` reads them from a file)")
+        .take(args)
+        .present_and_then(|o| crate::command_req::parse_params(o.value()))?;
+    let count: std::num::NonZeroUsize = noargs::opt("count")
+        .short('c')
+        .ty("INTEGER")
+        .doc("Count of requests generated by --method")
+        .default("1")
         .take(args)
         .then(|o| o.value().parse())?;
-    let timeout: Duration = noargs::opt("timeout")
+    let input: Option<std::path::PathBuf> = noargs::opt("input")
+        .short('i')
+        .ty("PATH")
+        .doc(concat!(
+            "Read requests from PATH ('-' for stdin)\n",
+            "(default: stdin, or nothing when --method is specified)"
+        ))
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let raw_send: bool = noargs::flag("raw-send")
+        .doc(concat!(
+            "Send each input line verbatim as a UDP packet without any JSON validation\n",
+            "(escapes such as `\\n` and `\\x00` are processed; for fuzzing servers)"
+        ))
+        .take(args)
+        .is_present();
+    let listen: Duration = noargs::opt("listen")
         .ty("SECONDS")
-        .doc("Read timeout for waiting responses")
-        .default("5")
+        .doc("How long to collect responses after sending in --raw-send mode (or in total with --receive-only)")
+        .default("1")
         .take(args)
         .then(|o| crate::utils::parse_duration_secs(o.value()))?;
+    let receive_only: bool = noargs::flag("receive-only")
+        .doc(concat!(
+            "Send nothing and print the JSON-RPC messages received from <SERVER> for --listen seconds\n",
+            "(invalid responses and notifications are reported and make the command fail)"
+        ))
+        .take(args)
+        .is_present();
+    let order: OutputOrder = noargs::opt("order")
+        .ty("arrival | id | preserve")
+        .doc(concat!(
+            "Order of the output responses: as received, sorted by id, or in the order of the requests\n",
+            "(`id` and `preserve` hold all responses in memory until every response has been received)"
+        ))
+        .default("arrival")
+        .take(args)
+        .then(|o| o.value().parse())?;
+    let group_by_method: Option<std::path::PathBuf> = noargs::opt("group-by-method")
+        .ty("DIR")
+        .doc(concat!(
+            "Write responses to DIR/METHOD.jsonl files (named after the request methods) instead of stdout\n",
+            "(responses whose ids do not match any request go to DIR/_unknown.jsonl;\n",
+            " notifications get no responses and thus appear in no file)"
+        ))
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let record: Option<std::path::PathBuf> = noargs::opt("record")
+        .ty("PATH")
+        .doc("Save all sent requests and received responses to PATH (for --replay)")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let replay: Option<std::path::PathBuf> = noargs::opt("replay")
+        .ty("PATH")
+        .doc(concat!(
+            "Re-send the requests recorded in PATH instead of reading them from stdin,\n",
+            "and report (and fail on) responses that differ from the recorded ones"
+        ))
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let tee: Option<std::path::PathBuf> = noargs::opt("tee")
+        .ty("PATH")
+        .doc("Write a hexdump of every raw outgoing / incoming packet (or --exec frame, headers included) to PATH")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let sockets: std::num::NonZeroUsize = noargs::opt("sockets")
+        .ty("COUNT")
+        .doc("Number of UDP sockets (local ports) that outgoing packets are distributed across")
+        .default("1")
+        .take(args)
+        .then(|o| o.value().parse())?;
+    let bind_ip: Option<std::net::IpAddr> = noargs::opt("bind")
+        .ty("IP_ADDR")
+        .doc("Local source IP address of the UDP socket (default: chosen by the OS)")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let bind_device: Option<String> = noargs::opt("bind-device")
+        .ty("INTERFACE")
+        .doc("Send and receive only through the network interface INTERFACE (Linux only; SO_BINDTODEVICE)")
+        .example("eth0")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let spawn_echo: bool = noargs::flag("spawn-echo")
+        .doc("Send the requests to an in-process echo server on an ephemeral port instead of <SERVER>")
+        .take(args)
+        .is_present();
+    let exec: Option<String> = noargs::opt("exec")
+        .ty("COMMAND")
+        .doc(concat!(
+            "Spawn COMMAND as the server and talk to it over its stdin / stdout instead of UDP\n",
+            "(COMMAND is split on whitespace; no shell quoting is applied)"
+        ))
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let transport = if let Some(command_line) = exec {
+        if spawn_echo {
+            return Err(noargs::Error::other(
+                args,
+                "--spawn-echo and --exec cannot be specified together",
+            ));
+        }
+        Transport::Exec(command_line)
+    } else if spawn_echo {
+        Transport::SpawnEcho
+    } else {
+        noargs::arg("<SERVER>")
+            .doc("JSON-RPC server address or hostname")
+            .example("127.0.0.1:8080")
+            .take(args)
+            .then(|a| crate::utils::parse_socket_addr(a.value()))
+            .map(Transport::Udp)?
+    };
 
     if args.metadata().help_mode {
         return Ok(true);
     }
 
-    run(server_addr, pretty, send_buf_size.get(), timeout)?;
+    if method.is_none() && params.is_some() {
+        return Err(noargs::Error::other(args, "--params requires --method"));
+    }
+    if raw_send && (method.is_some() || !matches!(transport, Transport::Udp(_))) {
+        return Err(noargs::Error::other(
+            args,
+            "--raw-send cannot be combined with --method, --exec or --spawn-echo",
+        ));
+    }
+    if receive_only
+        && (method.is_some()
+            || input.is_some()
+            || raw_send
+            || replay.is_some()
+            || !matches!(transport, Transport::Udp(_)))
+    {
+        return Err(noargs::Error::other(
+            args,
+            "--receive-only cannot be combined with --method, --input, --raw-send, --replay, --exec or --spawn-echo",
+        ));
+    }
+    if replay.is_some() && (method.is_some() || input.is_some() || raw_send) {
+        return Err(noargs::Error::other(
+            args,
+            "--replay cannot be combined with --method, --input or --raw-send",
+        ));
+    }
+    if table && (pretty || ndjson_out || group_by_method.is_some() || prefix.is_some()) {
+        return Err(noargs::Error::other(
+            args,
+            "--table cannot be combined with --pretty, --ndjson-out, --group-by-method or --prefix",
+        ));
+    }
+    if output_format != OutputFormat::Json && (pretty || ndjson_out || table || prefix.is_some()) {
+        return Err(noargs::Error::other(
+            args,
+            "--output-format msgpack cannot be combined with --pretty, --ndjson-out, --table or --prefix",
+        ));
+    }
+    if pretty && ndjson_out {
+        return Err(noargs::Error::other(
+            args,
+            "--pretty and --ndjson-out cannot be specified together",
+        ));
+    }
+
+    // `Duration::ZERO` is passed down as "wait forever" (`--block`, optionally with `--timeout 0`)
+    let timeout = match (timeout, block) {
+        (Some(timeout), true) if timeout.is_zero() => Duration::ZERO,
+        (Some(_), true) => {
+            return Err(noargs::Error::other(
+                args,
+                "--block cannot be combined with a nonzero --timeout",
+            ));
+        }
+        (Some(timeout), false) if timeout.is_zero() => {
+            return Err(noargs::Error::other(
+                args,
+                "--timeout 0 is not allowed (use --block to wait for responses forever)",
+            ));
+        }
+        (Some(timeout), false) => timeout,
+        (None, true) => Duration::ZERO,
+        (None, false) => Duration::from_secs(5),
+    };
+
+    let send_buf_size = match (send_buf_size, mtu) {
+        (Some(size), None) => size.get(),
+        (Some(_), Some(_)) => {
+            return Err(noargs::Error::other(
+                args,
+                "--mtu requires --send-buf-size to be `auto`",
+            ));
+        }
+        (None, None) => DEFAULT_SEND_BUF_SIZE,
+        (None, Some(mtu)) => {
+            let header_size = match transport {
+                Transport::Udp(addr) if addr.is_ipv6() => IPV6_HEADER_SIZE + UDP_HEADER_SIZE,
+                _ => IPV4_HEADER_SIZE + UDP_HEADER_SIZE,
+            };
+            if mtu <= header_size {
+                return Err(noargs::Error::other(
+                    args,
+                    format!(
+                        "--mtu must be greater than the IP and UDP header size ({header_size})"
+                    ),
+                ));
+            }
+            (mtu - header_size).min(MAX_UDP_PACKET)
+        }
+    };
+
+    let command = CallCommand {
+        transport,
+        pretty,
+        ndjson_out,
+        sort_keys,
+        table,
+        table_width,
+        compact_errors,
+        strip_id,
+        truncate_value,
+        prefix,
+        input_format,
+        #[cfg(feature = "msgpack")]
+        output_format,
+        allow_comments,
+        dedup,
+        only_ids,
+        overrides,
+        method_case,
+        max_line_length,
+        accept_version,
+        method,
+        params,
+        count: count.get(),
+        input,
+        max_response_size: max_response_size.unwrap_or(usize::MAX),
+        expect,
+        summary,
+        assertions,
+        strict_responses,
+        abort_on_first_error,
+        filter,
+        quiet,
+        raw_send,
+        receive_only,
+        listen,
+        order,
+        group_by_method,
+        record,
+        replay,
+        tee,
+        bind_ip,
+        bind_device,
+        sockets: sockets.get(),
+        send_buf_size,
+        warn_mtu,
+        framing,
+        flush_every,
+        timeout,
+        deadline,
+        verbosity,
+    };
+    command.run()?;
     Ok(true)
 }
 
-fn run(
-    server_addr: SocketAddr,
+enum Transport {
+    Udp(SocketAddr),
+    Exec(String),
+    SpawnEcho,
+}
+
+struct CallCommand {
+    transport: Transport,
     pretty: bool,
+    ndjson_out: bool,
+    sort_keys: bool,
+    table: bool,
+    table_width: usize,
+    compact_errors: bool,
+    strip_id: bool,
+    truncate_value: Option<usize>,
+    prefix: Option<String>,
+    input_format: crate::input::InputFormat,
+    #[cfg(feature = "msgpack")]
+    output_format: OutputFormat,
+    allow_comments: bool,
+    dedup: bool,
+    only_ids: Vec<nojson::RawJsonOwned>,
+    overrides: Vec<RequestOverride>,
+    method_case: crate::utils::MethodCase,
+    max_line_length: usize,
+    accept_version: crate::utils::AcceptVersion,
+    method: Option<String>,
+    params: Option<nojson::RawJsonOwned>,
+    count: usize,
+    input: Option<std::path::PathBuf>,
+    max_response_size: usize,
+    expect: Option<usize>,
+    summary: bool,
+    assertions: Vec<Assertion>,
+    strict_responses: bool,
+    abort_on_first_error: bool,
+    filter: Option<ResponseFilter>,
+    quiet: bool,
+    raw_send: bool,
+    receive_only: bool,
+    listen: Duration,
+    order: OutputOrder,
+    group_by_method: Option<std::path::PathBuf>,
+    record: Option<std::path::PathBuf>,
+    replay: Option<std::path::PathBuf>,
+    tee: Option<std::path::PathBuf>,
+    bind_ip: Option<std::net::IpAddr>,
+    bind_device: Option<String>,
+    sockets: usize,
     send_buf_size: usize,
+    warn_mtu: usize,
+    framing: crate::framing::Framing,
+    flush_every: usize,
     timeout: Duration,
-) -> crate::Result<()> {
-    let socket = connect_to_server_udp(server_addr)?;
-    socket.set_read_timeout(Some(timeout))?;
+    deadline: Option<Duration>,
+    verbosity: crate::Verbosity,
+}
 
-    let stdin = std::io::stdin();
-    let input_reader = std::io::BufReader::new(stdin.lock());
+impl CallCommand {
+    fn run(&self) -> crate::Result<()> {
+        let start_time = std::time::Instant::now();
+        let mut session = CallSession {
+            summary: CallSummary::default(),
+            tee: self.tee.as_deref().map(Tee::create).transpose()?,
+            assertion_failures: 0,
+            invalid_responses: Vec::new(),
+            aborted_by: None,
+            matched_responses: 0,
+            groups: self.group_by_method.clone().map(MethodGroups::new),
+            ordered: (self.order != OutputOrder::Arrival)
+                .then(|| OrderedResponses::new(self.order)),
+            seen_requests: self.dedup.then(std::collections::HashSet::new),
+            duplicates: 0,
+            selection: (!self.only_ids.is_empty()).then(|| IdSelection::new(&self.only_ids)),
+            request_deadlines: std::collections::HashMap::new(),
+            expired_requests: Vec::new(),
+            outstanding: std::collections::HashMap::new(),
+            table: self.table.then(ResponseTable::default),
+            mtu_warned: false,
+            recorder: self.record.as_deref().map(Recorder::create).transpose()?,
+            replay: self.replay.as_deref().map(Replay::load).transpose()?,
+        };
+        crate::utils::install_interrupt_handler();
+        let result = match &self.transport {
+            Transport::Udp(server_addr) if self.receive_only => {
+                self.run_receive_only(*server_addr, &mut session)
+            }
+            Transport::Udp(server_addr) if self.raw_send => {
+                self.run_raw_send(*server_addr, &mut session)
+            }
+            Transport::Udp(server_addr) => self.run_udp(*server_addr, &mut session),
+            Transport::Exec(command_line) => self.run_exec(command_line, &mut session),
+            Transport::SpawnEcho => {
+                let bind_ip = self.bind_ip.unwrap_or(std::net::Ipv4Addr::LOCALHOST.into());
+                crate::command_echo_server::spawn_echo_server(
+                    SocketAddr::new(bind_ip, 0),
+                    self.framing,
+                    self.verbosity,
+                )
+                .and_then(|server_addr| self.run_udp(server_addr, &mut session))
+            }
+        };
+        if let Some(selection) = &session.selection {
+            self.verbosity.info(format_args!(
+                "skipped {} requests not selected by --only-id",
+                selection.skipped
+            ));
+            for (id, _) in selection
+                .ids
+                .iter()
+                .zip(&selection.matched)
+                .filter(|(_, m)| !**m)
+            {
+                self.verbosity.warn(format_args!(
+                    "--only-id {} did not match any request",
+                    id.text()
+                ));
+            }
+        }
+        if session.duplicates > 0 {
+            self.verbosity.info(format_args!(
+                "dropped {} duplicate requests",
+                session.duplicates
+            ));
+        }
+        if let Some(ordered) = session.ordered.take() {
+            // Also written on errors (e.g. timeouts) so that received responses are not lost
+            let mut stdout = std::io::stdout().lock();
+            for line in ordered.into_sorted() {
+                if !self.emit_response(&mut stdout, &line, &mut session)? {
+                    break;
+                }
+            }
+        }
+        if let Some(table) = session.table.take() {
+            table.write(&mut std::io::stdout().lock())?;
+        }
+        if let Some(tee) = &mut session.tee {
+            tee.flush()?;
+        }
+        if let Some(recorder) = &mut session.recorder {
+            recorder.flush()?;
+        }
+        if let Some(groups) = &mut session.groups {
+            groups.flush()?;
+        }
+        if self.summary {
+            session.summary.elapsed = start_time.elapsed();
+            eprintln!("{}", nojson::Json(&session.summary));
+        }
+        if crate::utils::is_interrupted() {
+            let ids = session.outstanding_ids();
+            self.verbosity.warn(format_args!(
+                "interrupted; {} request(s) awaiting responses: [{}]",
+                ids.len(),
+                ids.join(",")
+            ));
+            std::process::exit(130);
+        }
+        result?;
+        if let Some(id) = &session.aborted_by {
+            let ids = session.outstanding_ids();
+            return Err(crate::Error::new(format!(
+                "aborted on the error response for request id {id}; {} request(s) unanswered: [{}]",
+                ids.len(),
+                ids.join(",")
+            )));
+        }
+        if self.quiet {
+            crate::utils::write_line(&mut std::io::stdout().lock(), session.matched_responses)?;
+        }
+        if let Some(replay) = &session.replay
+            && replay.mismatches > 0
+        {
+            return Err(crate::Error::new(format!(
+                "{} response(s) differ from the recording",
+                replay.mismatches
+            )));
+        }
+        if session.assertion_failures > 0 {
+            return Err(crate::Error::new(format!(
+                "{} assertion(s) failed",
+                session.assertion_failures
+            )));
+        }
+        if !session.expired_requests.is_empty() {
+            return Err(crate::Error::new(format!(
+                "{} request(s) got no response within their _timeout_ms (ids: {})",
+                session.expired_requests.len(),
+                session.expired_requests.join(",")
+            )));
+        }
+        if !session.invalid_responses.is_empty() {
+            return Err(crate::Error::new(format!(
+                "{} invalid response(s) (ids: {})",
+                session.invalid_responses.len(),
+                session.invalid_responses.join(",")
+            )));
+        }
+        Ok(())
+    }
 
-    let mut send_buf: Vec<u8> = Vec::with_capacity(send_buf_size);
-    let mut pending_responses = 0usize;
+    fn run_udp(&self, server_addr: SocketAddr, session: &mut CallSession) -> crate::Result<()> {
+        let mut sockets = Vec::with_capacity(self.sockets);
+        for _ in 0..self.sockets {
+            let socket =
+                connect_to_server_udp(server_addr, self.bind_ip, self.bind_device.as_deref())?;
+            self.verbosity.info(format_args!(
+                "connected to {server_addr} from {}",
+                socket.local_addr()?
+            ));
+            // A zero duration is rejected by `set_read_timeout()`, so it is mapped to "no timeout"
+            socket.set_read_timeout((!self.timeout.is_zero()).then_some(self.timeout))?;
+            sockets.push(socket);
+        }
+        // Outgoing packets are sent from the sockets in a round-robin manner
+        let mut sockets_iter = sockets.iter().cycle();
+        let mut next_socket = || sockets_iter.next().expect("at least one socket");
 
-    for line in input_reader.lines() {
-        let line = line?;
-        let json = nojson::RawJson::parse(&line)?;
-        let has_id = crate::utils::validate_json_rpc_request(json.value())?.is_some();
-        let request_len = line.len();
+        let mut send_buf: Vec<u8> = Vec::with_capacity(self.send_buf_size);
+        let mut buffered_requests = 0usize;
+        let mut pending_responses = 0usize;
 
-        if request_len > send_buf_size {
-            return Err(crate::Error::new("request size exceeds send-buf-size"));
-        }
+        for line in self.read_requests(session)? {
+            if crate::utils::is_interrupted() {
+                break;
+            }
+            let line = line?;
+            if session.is_duplicate(&line) || !session.is_selected(&line) {
+                continue;
+            }
+            let line = self.apply_overrides(line)?;
+            let line = session.take_timeout_annotation(line)?;
+            let has_id = session.count_request(&line, self.accept_version)?;
+            let request_len = line.len();
 
-        let extra = if send_buf.is_empty() { 0 } else { 1 };
-        if send_buf.len() + extra + request_len > send_buf_size {
-            flush_send_buf(&socket, &mut send_buf)?;
+            if self.framing.encoded_len(&[], request_len) > self.send_buf_size {
+                return Err(crate::Error::new("request size exceeds send-buf-size"));
+            }
+
+            if send_buf.len() + self.framing.encoded_len(&send_buf, request_len)
+                > self.send_buf_size
+            {
+                self.flush_send_buf(next_socket(), &mut send_buf, buffered_requests, session)?;
+                buffered_requests = 0;
+            }
+
+            self.framing.encode(&mut send_buf, line.as_bytes());
+            buffered_requests += 1;
+
+            if buffered_requests == self.flush_every {
+                self.flush_send_buf(next_socket(), &mut send_buf, buffered_requests, session)?;
+                buffered_requests = 0;
+            }
+
+            if has_id {
+                pending_responses += 1;
+            }
         }
 
         if !send_buf.is_empty() {
-            send_buf.push(b'\n');
+            self.flush_send_buf(next_socket(), &mut send_buf, buffered_requests, session)?;
         }
-        send_buf.extend_from_slice(line.as_bytes());
 
-        if has_id {
-            pending_responses += 1;
+        let expected_responses = self.expected_responses(pending_responses);
+        if expected_responses > 0 {
+            self.receive_responses(&sockets, expected_responses, session)?;
         }
+
+        Ok(())
     }
 
-    if !send_buf.is_empty() {
-        flush_send_buf(&socket, &mut send_buf)?;
+    // Unlike `run_udp()`, the input is neither parsed nor validated
+    fn run_raw_send(
+        &self,
+        server_addr: SocketAddr,
+        session: &mut CallSession,
+    ) -> crate::Result<()> {
+        let socket = connect_to_server_udp(server_addr, self.bind_ip, self.bind_device.as_deref())?;
+        self.verbosity.info(format_args!(
+            "connected to {server_addr} from {}",
+            socket.local_addr()?
+        ));
+
+        let mut input = self.open_input()?;
+        loop {
+            let mut line = String::new();
+            if crate::utils::read_line_with_limit(&mut input, &mut line, self.max_line_length)? == 0
+            {
+                break;
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            let packet = crate::utils::unescape_bytes(line).map_err(crate::Error::new)?;
+            let size = socket.send(&packet).map_err(|e| udp_error(&socket, e))?;
+            session.tee_record(Direction::Outgoing, &packet)?;
+            session.summary.packets_sent += 1;
+            session.summary.bytes_sent += size;
+            self.verbosity
+                .info(format_args!("sent raw packet: {size} bytes"));
+        }
+
+        let deadline = std::time::Instant::now() + self.listen;
+        let mut stdout = std::io::stdout().lock();
+        let mut recv_buf = vec![0u8; MAX_UDP_PACKET];
+        loop {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            if remaining.is_zero() {
+                return Ok(());
+            }
+            socket.set_read_timeout(Some(remaining))?;
+            let bytes_read = match socket.recv(&mut recv_buf) {
+                Ok(size) => size,
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    return Ok(());
+                }
+                Err(e) => return Err(udp_error(&socket, e)),
+            };
+            self.verbosity
+                .info(format_args!("received packet: {bytes_read} bytes"));
+            session.summary.packets_received += 1;
+            session.summary.bytes_received += bytes_read;
+            session.tee_record(Direction::Incoming, &recv_buf[..bytes_read])?;
+            let text = String::from_utf8_lossy(&recv_buf[..bytes_read]);
+            if !crate::utils::write_line(&mut stdout, text.trim_end_matches('\n'))? {
+                return Ok(());
+            }
+        }
     }
 
-    if pending_responses > 0 {
-        receive_responses(&socket, pending_responses, pretty)?;
+    fn run_receive_only(
+        &self,
+        server_addr: SocketAddr,
+        session: &mut CallSession,
+    ) -> crate::Result<()> {
+        let socket = connect_to_server_udp(server_addr, self.bind_ip, self.bind_device.as_deref())?;
+        self.verbosity.info(format_args!(
+            "listening for messages from {server_addr} on {}",
+            socket.local_addr()?
+        ));
+
+        let deadline = std::time::Instant::now() + self.listen;
+        let mut stdout = std::io::stdout().lock();
+        let mut recv_buf = vec![0u8; MAX_UDP_PACKET];
+        loop {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            if remaining.is_zero() {
+                return Ok(());
+            }
+            socket.set_read_timeout(Some(remaining))?;
+            let bytes_read = match socket.recv(&mut recv_buf) {
+                Ok(size) => size,
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    return Ok(());
+                }
+                Err(e) => return Err(udp_error(&socket, e)),
+            };
+            self.verbosity
+                .info(format_args!("received packet: {bytes_read} bytes"));
+            session.summary.packets_received += 1;
+            session.summary.bytes_received += bytes_read;
+            session.tee_record(Direction::Incoming, &recv_buf[..bytes_read])?;
+
+            let mut reader = &recv_buf[..bytes_read];
+            while let Some(message) = self
+                .framing
+                .read_message_with_limit(&mut reader, self.max_response_size)?
+            {
+                if !self.output_response(&mut stdout, &message, session)? {
+                    return Ok(());
+                }
+                session.summary.responses += 1;
+            }
+        }
     }
 
-    Ok(())
-}
+    fn flush_send_buf(
+        &self,
+        socket: &UdpSocket,
+        send_buf: &mut Vec<u8>,
+        requests: usize,
+        session: &mut CallSession,
+    ) -> crate::Result<()> {
+        let header_size = if socket.peer_addr()?.is_ipv6() {
+            IPV6_HEADER_SIZE + UDP_HEADER_SIZE
+        } else {
+            IPV4_HEADER_SIZE + UDP_HEADER_SIZE
+        };
+        if send_buf.len() + header_size > self.warn_mtu && !session.mtu_warned {
+            self.verbosity.warn(format_args!(
+                "outgoing packet of {} bytes (plus {header_size} bytes of headers) exceeds --warn-mtu {} and may be fragmented (further warnings suppressed)",
+                send_buf.len(),
+                self.warn_mtu
+            ));
+            session.mtu_warned = true;
+        }
 
-fn connect_to_server_udp(server_addr: SocketAddr) -> crate::Result<UdpSocket> {
-    let bind_addr = client_bind_addr_for_server(server_addr);
-    let socket = UdpSocket::bind(bind_addr)?;
-    socket.connect(server_addr)?;
-    Ok(socket)
-}
+        let size = socket.send(send_buf).map_err(|e| udp_error(socket, e))?;
+        if size != send_buf.len() {
+            return Err(crate::Error::new("failed to send complete request packet"));
+        }
+        session.tee_record(Direction::Outgoing, send_buf)?;
+        session.summary.packets_sent += 1;
+        session.summary.bytes_sent += size;
+        self.verbosity.info(format_args!(
+            "sent packet: {size} bytes, {requests} requests"
+        ));
+        send_buf.clear();
+        Ok(())
+    }
 
-fn client_bind_addr_for_server(server_addr: SocketAddr) -> SocketAddr {
-    match server_addr {
-        SocketAddr::V4(addr) => {
-            let ip = if addr.ip().is_loopback() {
-                std::net::Ipv4Addr::LOCALHOST
-            } else {
-                std::net::Ipv4Addr::UNSPECIFIED
-            };
-            SocketAddr::from((ip, 0))
+    fn receive_responses(
+        &self,
+        sockets: &[UdpSocket],
+        expected: usize,
+        session: &mut CallSession,
+    ) -> crate::Result<()> {
+        let start_time = std::time::Instant::now();
+        let mut stdout = std::io::stdout().lock();
+        let mut recv_buf = vec![0u8; MAX_UDP_PACKET];
+        let mut received = 0usize;
+        let mut receiver = PacketReceiver::new(sockets)?;
+        let deadline = self.deadline.map(|d| start_time + d);
+        while received + session.expired_requests.len() < expected {
+            let wait = self.next_wait(session, deadline, received, expected)?;
+            let bytes_read =
+                match recv_interruptibly(wait, |slice| receiver.recv(&mut recv_buf, Some(slice))) {
+                    Ok(Some(size)) => size,
+                    Ok(None) => return Ok(()),
+                    Err(e)
+                        if e.kind() == std::io::ErrorKind::WouldBlock
+                            || e.kind() == std::io::ErrorKind::TimedOut =>
+                    {
+                        if session.expire_requests(self.verbosity) > 0 {
+                            continue;
+                        }
+                        return Err(crate::Error::new(format!(
+                            "timed out waiting for responses (received {received} of {expected})"
+                        )));
+                    }
+                    Err(e) => return Err(udp_error(&sockets[0], e)),
+                };
+            self.verbosity
+                .info(format_args!("received packet: {bytes_read} bytes"));
+            session.summary.packets_received += 1;
+            session.summary.bytes_received += bytes_read;
+            session.tee_record(Direction::Incoming, &recv_buf[..bytes_read])?;
+
+            let mut reader = &recv_buf[..bytes_read];
+            while received + session.expired_requests.len() < expected
+                && let Some(line) = self
+                    .framing
+                    .read_message_with_limit(&mut reader, self.max_response_size)?
+            {
+                self.verbosity.debug(format_args!(
+                    "received response #{} after {:?}",
+                    received + 1,
+                    start_time.elapsed()
+                ));
+                if !self.output_response(&mut stdout, &line, session)? {
+                    return Ok(());
+                }
+                received += 1;
+                session.summary.responses += 1;
+            }
         }
-        SocketAddr::V6(addr) => {
-            let ip = if addr.ip().is_loopback() {
-                std::net::Ipv6Addr::LOCALHOST
-            } else {
-                std::net::Ipv6Addr::UNSPECIFIED
+        Ok(())
+    }
+
+    fn run_exec(&self, command_line: &str, session: &mut CallSession) -> crate::Result<()> {
+        let mut tokens = command_line.split_whitespace();
+        let program = tokens
+            .next()
+            .ok_or_else(|| crate::Error::new("exec command must not be empty"))?;
+        let mut child = std::process::Command::new(program)
+            .args(tokens)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .map_err(|e| crate::Error::new(format!("failed to spawn {program:?}: {e}")))?;
+        self.verbosity
+            .info(format_args!("spawned server process (pid={})", child.id()));
+
+        // Responses are read on a separate thread so that a server writing large outputs
+        // cannot deadlock against the requests being written to its stdin
+        let child_stdout = child.stdout.take().expect("piped stdout");
+        let framing = self.framing;
+        let max_response_size = self.max_response_size;
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut reader = std::io::BufReader::new(child_stdout);
+            while let Some(result) = framing
+                .read_raw_message_with_limit(&mut reader, max_response_size)
+                .transpose()
+            {
+                let is_err = result.is_err();
+                if tx.send(result).is_err() || is_err {
+                    break;
+                }
+            }
+        });
+
+        let mut child_stdin = std::io::BufWriter::new(child.stdin.take().expect("piped stdin"));
+        let mut pending_responses = 0usize;
+        for line in self.read_requests(session)? {
+            if crate::utils::is_interrupted() {
+                break;
+            }
+            let line = line?;
+            if session.is_duplicate(&line) || !session.is_selected(&line) {
+                continue;
+            }
+            let line = self.apply_overrides(line)?;
+            let line = session.take_timeout_annotation(line)?;
+            let has_id = session.count_request(&line, self.accept_version)?;
+            if has_id {
+                pending_responses += 1;
+            }
+            // Encoded up front so that `--tee` records the bytes as written, headers included
+            let mut frame = Vec::new();
+            self.framing.write_message(&mut frame, line.as_bytes())?;
+            child_stdin.write_all(&frame)?;
+            session.summary.bytes_sent += line.len();
+            session.tee_record(Direction::Outgoing, &frame)?;
+        }
+        child_stdin.flush()?;
+        drop(child_stdin); // Closes the pipe so that the server can observe EOF
+
+        let start_time = std::time::Instant::now();
+        let mut stdout = std::io::stdout().lock();
+        let mut received = 0usize;
+        let expected_responses = self.expected_responses(pending_responses);
+        let deadline = self.deadline.map(|d| start_time + d);
+        while received + session.expired_requests.len() < expected_responses {
+            let wait = match self.next_wait(session, deadline, received, expected_responses) {
+                Ok(wait) => wait,
+                Err(e) => {
+                    let _ = child.kill();
+                    return Err(e);
+                }
             };
-            SocketAddr::from((ip, 0))
+            let result = recv_interruptibly(wait, |slice| match rx.recv_timeout(slice) {
+                Ok(message) => Ok(Some(message)),
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                    Err(std::io::ErrorKind::TimedOut.into())
+                }
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => Ok(None),
+            });
+            let (message, frame) = match result {
+                Ok(Some(Some(message))) => message?,
+                Ok(None) => {
+                    let _ = child.kill();
+                    break;
+                }
+                Err(_) if session.expire_requests(self.verbosity) > 0 => continue,
+                Err(_) => {
+                    let _ = child.kill();
+                    return Err(crate::Error::new(format!(
+                        "timed out waiting for responses (received {received} of {expected_responses})"
+                    )));
+                }
+                Ok(Some(None)) => {
+                    let status = child.wait()?;
+                    return Err(crate::Error::new(format!(
+                        "server process exited with {status} before sending all responses (received {received} of {expected_responses})"
+                    )));
+                }
+            };
+            self.verbosity.debug(format_args!(
+                "received response #{} after {:?}",
+                received + 1,
+                start_time.elapsed()
+            ));
+            session.summary.bytes_received += message.len();
+            session.tee_record(Direction::Incoming, &frame)?;
+            if !self.output_response(&mut stdout, &message, session)? {
+                break;
+            }
+            received += 1;
+            session.summary.responses += 1;
+        }
+
+        let Some(status) = wait_child_exit(&mut child, self.timeout)? else {
+            self.verbosity
+                .warn("server process did not exit after its stdin was closed; killed it");
+            return Ok(());
+        };
+        if !status.success() {
+            self.verbosity
+                .warn(format_args!("server process exited with {status}"));
+            return Err(crate::Error::new(format!(
+                "server process exited with {status}"
+            )));
         }
+        self.verbosity
+            .info(format_args!("server process exited with {status}"));
+        Ok(())
     }
-}
 
-fn flush_send_buf(socket: &UdpSocket, send_buf: &mut Vec<u8>) -> crate::Result<()> {
-    let size = socket.send(send_buf)?;
-    if size != send_buf.len() {
-        return Err(crate::Error::new("failed to send complete request packet"));
+    // Time to wait for the next response: until the earliest `_timeout_ms` deadline of the pending
+    // requests, or `--timeout` while requests without the annotation are pending, capped by the
+    // time remaining until `--deadline` (`None` means waiting forever)
+    fn next_wait(
+        &self,
+        session: &CallSession,
+        deadline: Option<std::time::Instant>,
+        received: usize,
+        expected: usize,
+    ) -> crate::Result<Option<Duration>> {
+        let now = std::time::Instant::now();
+        let annotated = session
+            .request_deadlines
+            .values()
+            .min()
+            .map(|d| d.saturating_duration_since(now));
+        let only_annotated = !session.outstanding.is_empty()
+            && session
+                .outstanding
+                .keys()
+                .all(|id| session.request_deadlines.contains_key(id));
+        let idle = (!only_annotated && !self.timeout.is_zero()).then_some(self.timeout);
+        let wait = match (idle, annotated) {
+            (Some(idle), Some(annotated)) => Some(idle.min(annotated)),
+            (idle, annotated) => idle.or(annotated),
+        };
+        let Some(deadline) = deadline else {
+            return Ok(wait);
+        };
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        if remaining.is_zero() {
+            return Err(crate::Error::new(format!(
+                "deadline exceeded waiting for responses (received {received} of {expected})"
+            )));
+        }
+        Ok(Some(wait.map_or(remaining, |wait| wait.min(remaining))))
     }
-    send_buf.clear();
-    Ok(())
-}
 
-fn receive_responses(socket: &UdpSocket, expected: usize, pretty: bool) -> crate::Result<()> {
-    let mut recv_buf = vec![0u8; MAX_UDP_PACKET];
-    let mut received = 0usize;
-    while received < expected {
-        let bytes_read = match socket.recv(&mut recv_buf) {
-            Ok(size) => size,
-            Err(e)
-                if e.kind() == std::io::ErrorKind::WouldBlock
-                    || e.kind() == std::io::ErrorKind::TimedOut =>
-            {
-                return Err(crate::Error::new(format!(
-                    "timed out waiting for responses (received {received} of {expected})"
-                )));
+    fn expected_responses(&self, pending_responses: usize) -> usize {
+        let Some(expect) = self.expect else {
+            return pending_responses;
+        };
+        if expect != pending_responses {
+            self.verbosity.warn(format_args!(
+                "--expect {expect} differs from the number of requests awaiting responses ({pending_responses})"
+            ));
+        }
+        expect
+    }
+
+    fn read_requests(
+        &self,
+        session: &mut CallSession,
+    ) -> crate::Result<Box<dyn '_ + Iterator<Item = crate::Result<String>>>> {
+        if let Some(replay) = &mut session.replay {
+            let requests = std::mem::take(&mut replay.requests);
+            return Ok(Box::new(requests.into_iter().map(Ok)));
+        }
+
+        let generated = self.method.iter().flat_map(move |method| {
+            (0..self.count).map(move |id| {
+                Ok(
+                    crate::command_req::request_json(method, self.params.as_ref(), Some(id))
+                        .to_string(),
+                )
+            })
+        });
+
+        if self.input.is_none() && self.method.is_some() {
+            return Ok(Box::new(generated));
+        }
+        let requests = crate::input::RequestReader::new(self.open_input()?, self.input_format)
+            .allow_comments(self.allow_comments)
+            .max_line_length(self.max_line_length);
+        Ok(Box::new(generated.chain(requests)))
+    }
+
+    fn apply_overrides(&self, mut line: String) -> crate::Result<String> {
+        for RequestOverride { pointer, value } in &self.overrides {
+            let json = nojson::RawJson::parse(&line)?;
+            line = crate::utils::set_json_pointer(json.value(), pointer, value.value())
+                .map_err(crate::Error::new)?;
+        }
+        if self.method_case != crate::utils::MethodCase::AsIs {
+            let json = nojson::RawJson::parse(&line)?;
+            // Requests without a string method are left for the later validation to reject
+            let method = json
+                .value()
+                .to_member("method")
+                .ok()
+                .and_then(|m| m.get())
+                .and_then(|m| m.to_unquoted_string_str().ok());
+            if let Some(method) = method {
+                let normalized = nojson::Json(self.method_case.apply(&method)).to_string();
+                let normalized = nojson::RawJson::parse(&normalized)?;
+                line = crate::utils::set_json_pointer(json.value(), "/method", normalized.value())
+                    .map_err(crate::Error::new)?;
+            }
+        }
+        Ok(line)
+    }
+
+    fn open_input(&self) -> crate::Result<Box<dyn std::io::BufRead>> {
+        match &self.input {
+            Some(path) if path.as_os_str() != "-" => {
+                let file = std::fs::File::open(path).map_err(|e| {
+                    crate::Error::new(format!("failed to open {}: {e}", path.display()))
+                })?;
+                Ok(Box::new(std::io::BufReader::new(file)))
+            }
+            _ => Ok(Box::new(crate::utils::InterruptibleReader(
+                std::io::stdin().lock(),
+            ))),
+        }
+    }
+
+    fn output_response<W: std::io::Write>(
+        &self,
+        writer: &mut W,
+        line: &str,
+        session: &mut CallSession,
+    ) -> crate::Result<bool> {
+        let json = match nojson::RawJson::parse(line) {
+            Ok(json) => json,
+            Err(e) => {
+                self.verbosity
+                    .warn(format_args!("invalid response for request id null: {e}"));
+                session.invalid_responses.push("null".to_owned());
+                return Ok(true);
             }
-            Err(e) => return Err(e.into()),
         };
+        let response = json.value();
+        if self.receive_only
+            && response.kind() == nojson::JsonValueKind::Object
+            && response.to_member("method")?.get().is_some()
+        {
+            // Requests and notifications initiated by the server
+            if let Err(e) = crate::utils::validate_json_rpc_request(response, self.accept_version) {
+                self.verbosity.warn(format_args!(
+                    "invalid message from the server (id {}): {e}",
+                    response_id(response)
+                ));
+                session
+                    .invalid_responses
+                    .push(response_id(response).to_owned());
+                return Ok(true);
+            }
+            session.record(Direction::Incoming, line)?;
+            return self.print_response(writer, line, session);
+        }
+        let id = match validate_response_and_parse_id(response, self.strict_responses) {
+            Ok(id) => id,
+            Err(e) => {
+                // The request is answered (albeit badly), so it is no longer awaited
+                session.request_deadlines.remove(e.id);
+                session.outstanding.remove(e.id);
+                self.verbosity.warn(&e);
+                session.invalid_responses.push(e.id.to_owned());
+                return Ok(true);
+            }
+        };
+        session.record(Direction::Incoming, line)?;
+        session.request_deadlines.remove(id);
+        session.outstanding.remove(id);
+        if self.abort_on_first_error && response.to_member("error")?.get().is_some() {
+            session.aborted_by = Some(id.to_owned());
+        }
+        if let Some(replay) = &mut session.replay {
+            replay.compare(line, self.verbosity)?;
+        }
+        self.check_assertions(line, session)?;
+        let written = self.print_response(writer, line, session)?;
+        Ok(written && session.aborted_by.is_none())
+    }
+
+    fn print_response<W: std::io::Write>(
+        &self,
+        writer: &mut W,
+        line: &str,
+        session: &mut CallSession,
+    ) -> crate::Result<bool> {
+        if let Some(filter) = &self.filter {
+            let json = nojson::RawJson::parse(line)?;
+            if !filter.matches(json.value()) {
+                return Ok(true);
+            }
+        }
+        session.matched_responses += 1;
+        if self.quiet {
+            return Ok(true);
+        }
+        if let Some(ordered) = &mut session.ordered {
+            ordered.push_response(line)?;
+            return Ok(true);
+        }
+        self.emit_response(writer, line, session)
+    }
+
+    fn emit_response<W: std::io::Write>(
+        &self,
+        writer: &mut W,
+        line: &str,
+        session: &mut CallSession,
+    ) -> crate::Result<bool> {
+        if let Some(table) = &mut session.table {
+            table.push_response(line, self.table_width)?;
+            return Ok(true);
+        }
+        if let Some(groups) = &mut session.groups {
+            let writer = groups.writer_for(line)?;
+            return self.write_response(writer, line);
+        }
+        self.write_response(writer, line)
+    }
+
+    fn check_assertions(&self, line: &str, session: &mut CallSession) -> crate::Result<()> {
+        if self.assertions.is_empty() {
+            return Ok(());
+        }
+        let json = nojson::RawJson::parse(line)?;
+        let id = response_id(json.value());
+        for assertion in &self.assertions {
+            if let Err(reason) = assertion.check(json.value()) {
+                self.verbosity.warn(format_args!(
+                    "assertion failed for request id {id}: {}: {reason}",
+                    assertion.pointer
+                ));
+                session.assertion_failures += 1;
+            }
+        }
+        Ok(())
+    }
 
-        let text = std::str::from_utf8(&recv_buf[..bytes_read])?;
-        for line in text.lines() {
-            if pretty {
-                let json = nojson::RawJson::parse(line)?;
-                let pretty_json = nojson::json(|f| {
+    fn write_response<W: std::io::Write>(&self, writer: &mut W, line: &str) -> crate::Result<bool> {
+        let compacted;
+        let line = if self.compact_errors {
+            compacted = compact_error(line)?;
+            compacted.as_deref().unwrap_or(line)
+        } else {
+            line
+        };
+        let stripped;
+        let line = if self.strip_id {
+            stripped = strip_id(line)?;
+            &stripped
+        } else {
+            line
+        };
+        let truncated;
+        let line = if let Some(max) = self.truncate_value {
+            let json = nojson::RawJson::parse(line)?;
+            truncated = nojson::json(|f| f.value(TruncatedStrings(json.value(), max))).to_string();
+            &truncated
+        } else {
+            line
+        };
+        #[cfg(feature = "msgpack")]
+        if self.output_format == OutputFormat::Msgpack {
+            let json = nojson::RawJson::parse(line)?;
+            let mut packed = Vec::new();
+            if self.sort_keys {
+                let sorted = nojson::json(|f| f.value(crate::utils::SortedKeys(json.value())));
+                let sorted = nojson::RawJson::parse(&sorted.to_string())?.into_owned();
+                crate::msgpack::encode_json(sorted.value(), &mut packed)?;
+            } else {
+                crate::msgpack::encode_json(json.value(), &mut packed)?;
+            }
+            return match writer.write_all(&packed) {
+                Ok(()) => Ok(true),
+                Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(false),
+                Err(e) => Err(e.into()),
+            };
+        }
+        let written = if self.pretty || self.ndjson_out || self.sort_keys {
+            let json = nojson::RawJson::parse(line)?;
+            let formatted = nojson::json(|f| {
+                if self.pretty {
                     f.set_indent_size(2);
                     f.set_spacing(true);
+                }
+                if self.sort_keys {
+                    f.value(crate::utils::SortedKeys(json.value()))
+                } else {
                     f.value(json.value())
-                });
-                println!("{pretty_json}");
-            } else {
-                println!("{line}");
-            }
-            received += 1;
+                }
+            });
+            self.write_prefixed_line(writer, formatted)?
+        } else {
+            self.write_prefixed_line(writer, line)?
+        };
+        Ok(written)
+    }
+
+    fn write_prefixed_line<W, T>(&self, writer: &mut W, line: T) -> std::io::Result<bool>
+    where
+        W: std::io::Write,
+        T: std::fmt::Display,
+    {
+        match &self.prefix {
+            Some(prefix) => crate::utils::write_line(writer, format_args!("{prefix}\t{line}")),
+            None => crate::utils::write_line(writer, line),
+        }
+    }
+}
+
+// Reduces an error response to `{"id":...,"code":...,"message":...}` (`None` for other responses)
+fn compact_error(line: &str) -> crate::Result<Option<String>> {
+    let json = nojson::RawJson::parse(line)?;
+    let Some(error) = json.value().to_member("error").ok().and_then(|m| m.get()) else {
+        return Ok(None);
+    };
+    let id = json.value().to_member("id")?.get();
+    let code = error.to_member("code").ok().and_then(|m| m.get());
+    let message = error.to_member("message").ok().and_then(|m| m.get());
+    let compacted = nojson::object(|f| {
+        f.member("id", id)?;
+        f.member("code", code)?;
+        f.member("message", message)
+    });
+    Ok(Some(compacted.to_string()))
+}
+
+// Reserializes the response without its `id` member
+fn strip_id(line: &str) -> crate::Result<String> {
+    let json = nojson::RawJson::parse(line)?;
+    if json.value().kind() != nojson::JsonValueKind::Object {
+        return Ok(line.to_owned());
+    }
+    let mut members = Vec::new();
+    for (name, value) in json.value().to_object()? {
+        let name = name.to_unquoted_string_str()?;
+        if name != "id" {
+            members.push((name, value));
+        }
+    }
+    Ok(nojson::object(|f| f.members(members.iter().map(|(n, v)| (n, *v)))).to_string())
+}
+
+// Calls `recv` with slices of `wait` (`None` means forever) until it succeeds, fails with other
+// than a timeout, or the whole `wait` elapses; returns `Ok(None)` once Ctrl-C is pressed
+fn recv_interruptibly<T>(
+    wait: Option<Duration>,
+    mut recv: impl FnMut(Duration) -> std::io::Result<T>,
+) -> std::io::Result<Option<T>> {
+    let deadline = wait.map(|wait| std::time::Instant::now() + wait);
+    loop {
+        if crate::utils::is_interrupted() {
+            return Ok(None);
+        }
+        let slice = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+                if remaining.is_zero() {
+                    return Err(std::io::ErrorKind::TimedOut.into());
+                }
+                remaining.min(INTERRUPT_POLL_INTERVAL)
+            }
+            None => INTERRUPT_POLL_INTERVAL,
+        };
+        match recv(slice) {
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock
+                        | std::io::ErrorKind::TimedOut
+                        | std::io::ErrorKind::Interrupted
+                ) => {}
+            result => return result.map(Some),
+        }
+    }
+}
+
+// Connected UDP sockets report ICMP port-unreachable messages as `ConnectionRefused`
+fn udp_error(socket: &UdpSocket, e: std::io::Error) -> crate::Error {
+    if e.kind() != std::io::ErrorKind::ConnectionRefused {
+        return e.into();
+    }
+    match socket.peer_addr() {
+        Ok(addr) => crate::Error::new(format!(
+            "server {addr} refused connection (no UDP listener?)"
+        )),
+        Err(_) => crate::Error::new("server refused connection (no UDP listener?)"),
+    }
+}
+
+pub(crate) fn connect_to_server_udp(
+    server_addr: SocketAddr,
+    bind_ip: Option<std::net::IpAddr>,
+    bind_device: Option<&str>,
+) -> crate::Result<UdpSocket> {
+    let bind_addr = match bind_ip {
+        Some(ip) if ip.is_ipv4() != server_addr.is_ipv4() => {
+            return Err(crate::Error::new(format!(
+                "bind address {ip} and server address {server_addr} belong to different IP families"
+            )));
+        }
+        Some(ip) => SocketAddr::new(ip, 0),
+        None => client_bind_addr_for_server(server_addr),
+    };
+    let socket = UdpSocket::bind(bind_addr)
+        .map_err(|e| crate::Error::new(format!("failed to bind UDP socket to {bind_addr}: {e}")))?;
+    if let Some(device) = bind_device {
+        crate::utils::bind_to_device(&socket, device)?;
+    }
+    socket.connect(server_addr)?;
+    Ok(socket)
+}
+
+fn client_bind_addr_for_server(server_addr: SocketAddr) -> SocketAddr {
+    match server_addr {
+        SocketAddr::V4(addr) => {
+            let ip = if addr.ip().is_loopback() {
+                std::net::Ipv4Addr::LOCALHOST
+            } else {
+                std::net::Ipv4Addr::UNSPECIFIED
+            };
+            SocketAddr::from((ip, 0))
+        }
+        SocketAddr::V6(addr) => {
+            let ip = if addr.ip().is_loopback() {
+                std::net::Ipv6Addr::LOCALHOST
+            } else {
+                std::net::Ipv6Addr::UNSPECIFIED
+            };
+            SocketAddr::from((ip, 0))
+        }
+    }
+}
+
+// Receives packets from one socket directly, or from multiple sockets via per-socket threads
+enum PacketReceiver<'a> {
+    Single(&'a UdpSocket),
+    Multi(std::sync::mpsc::Receiver<std::io::Result<Vec<u8>>>),
+}
+
+impl<'a> PacketReceiver<'a> {
+    fn new(sockets: &'a [UdpSocket]) -> crate::Result<Self> {
+        if let [socket] = sockets {
+            return Ok(Self::Single(socket));
+        }
+        let (tx, rx) = std::sync::mpsc::channel();
+        for socket in sockets {
+            let socket = socket.try_clone()?;
+            // Timeouts are handled by the channel, so the threads block until the process exits
+            socket.set_read_timeout(None)?;
+            let tx = tx.clone();
+            std::thread::spawn(move || {
+                let mut recv_buf = vec![0u8; MAX_UDP_PACKET];
+                loop {
+                    let result = socket.recv(&mut recv_buf).map(|n| recv_buf[..n].to_vec());
+                    let is_err = result.is_err();
+                    if tx.send(result).is_err() || is_err {
+                        break;
+                    }
+                }
+            });
+        }
+        Ok(Self::Multi(rx))
+    }
+
+    // `None` timeout means waiting forever
+    fn recv(&mut self, buf: &mut [u8], timeout: Option<Duration>) -> std::io::Result<usize> {
+        let rx = match self {
+            Self::Single(socket) => {
+                socket.set_read_timeout(timeout)?;
+                return socket.recv(buf);
+            }
+            Self::Multi(rx) => rx,
+        };
+        let result = match timeout {
+            Some(timeout) => rx.recv_timeout(timeout).ok(),
+            None => rx.recv().ok(),
+        };
+        // `None` is either a timeout of `recv_timeout()` or a closed channel; the receiver threads
+        // never time out, so the channel closes only after every one of them has failed (and each
+        // failure has already been delivered as an `Err` packet)
+        let packet = result.unwrap_or_else(|| Err(std::io::ErrorKind::TimedOut.into()))?;
+        buf[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+}
+
+// Mutable state of a single `CallCommand::run()` invocation
+#[derive(Debug)]
+struct CallSession {
+    summary: CallSummary,
+    tee: Option<Tee>,
+    assertion_failures: usize,
+    // Ids (raw JSON) of the responses rejected by `validate_response_and_parse_id()`
+    invalid_responses: Vec<String>,
+    // Id (raw JSON) of the error response that stopped the run with `--abort-on-first-error`
+    aborted_by: Option<String>,
+    matched_responses: usize,
+    groups: Option<MethodGroups>,
+    ordered: Option<OrderedResponses>,
+    seen_requests: Option<std::collections::HashSet<String>>,
+    duplicates: usize,
+    selection: Option<IdSelection>,
+    // Request id (raw JSON) => deadline given by `_timeout_ms` of the requests awaiting responses
+    request_deadlines: std::collections::HashMap<String, std::time::Instant>,
+    // Ids (raw JSON) of the requests given up on by `expire_requests()`
+    expired_requests: Vec<String>,
+    // Request id (raw JSON) => sequence number of the requests awaiting responses
+    outstanding: std::collections::HashMap<String, usize>,
+    table: Option<ResponseTable>,
+    mtu_warned: bool,
+    recorder: Option<Recorder>,
+    replay: Option<Replay>,
+}
+
+impl CallSession {
+    fn record(&mut self, direction: Direction, message: &str) -> crate::Result<()> {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(direction, message)?;
+        }
+        Ok(())
+    }
+
+    // Only tracks requests when `--dedup` is specified
+    fn is_duplicate(&mut self, line: &str) -> bool {
+        let Some(seen) = &mut self.seen_requests else {
+            return false;
+        };
+        if seen.contains(line) {
+            self.duplicates += 1;
+            return true;
+        }
+        seen.insert(line.to_owned());
+        false
+    }
+
+    // Ids (raw JSON) of the requests awaiting responses, in the order they were sent
+    fn outstanding_ids(&self) -> Vec<&str> {
+        let mut outstanding: Vec<_> = self.outstanding.iter().collect();
+        outstanding.sort_by_key(|(_, seqno)| **seqno);
+        outstanding.into_iter().map(|(id, _)| id.as_str()).collect()
+    }
+
+    // Invalid requests are selected so that they are reported by `count_request()`
+    fn is_selected(&mut self, line: &str) -> bool {
+        let Some(selection) = &mut self.selection else {
+            return true;
+        };
+        let Ok(json) = nojson::RawJson::parse(line) else {
+            return true;
+        };
+        let Some(id) = json.value().to_member("id").ok().and_then(|m| m.get()) else {
+            selection.skipped += 1;
+            return false;
+        };
+        let mut selected = false;
+        for (expected, matched) in selection.ids.iter().zip(&mut selection.matched) {
+            if crate::utils::json_values_equal(id, expected.value()) {
+                *matched = true;
+                selected = true;
+            }
+        }
+        if !selected {
+            selection.skipped += 1;
+        }
+        selected
+    }
+
+    // Removes the nonstandard `_timeout_ms` member from the request and remembers its value
+    fn take_timeout_annotation(&mut self, line: String) -> crate::Result<String> {
+        let json = nojson::RawJson::parse(&line)?;
+        let Some(annotation) = json
+            .value()
+            .to_member("_timeout_ms")
+            .ok()
+            .and_then(|m| m.get())
+        else {
+            return Ok(line);
+        };
+        let millis: u64 = annotation.try_into()?;
+        let mut members = Vec::new();
+        for (name, value) in json.value().to_object()? {
+            let name = name.to_unquoted_string_str()?;
+            if name != "_timeout_ms" {
+                members.push((name, value));
+            }
+        }
+        if let Some(id) = json.value().to_member("id")?.get() {
+            let deadline = std::time::Instant::now() + Duration::from_millis(millis);
+            self.request_deadlines
+                .insert(id.as_raw_str().to_owned(), deadline);
+        }
+        Ok(nojson::object(|f| f.members(members.iter().map(|(n, v)| (n, *v)))).to_string())
+    }
+
+    // Gives up on the pending requests whose `_timeout_ms` deadline has passed and returns how many
+    fn expire_requests(&mut self, verbosity: crate::Verbosity) -> usize {
+        let now = std::time::Instant::now();
+        let mut expired: Vec<_> = self
+            .request_deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+        expired.sort_by_key(|id| self.outstanding.get(id).copied());
+        for id in &expired {
+            verbosity.warn(format_args!(
+                "no response for request id {id} within its _timeout_ms"
+            ));
+            self.request_deadlines.remove(id);
+            self.outstanding.remove(id);
+        }
+        let count = expired.len();
+        self.expired_requests.extend(expired);
+        count
+    }
+
+    // Validates the request and returns whether it has an id (i.e., awaits a response)
+    fn count_request(
+        &mut self,
+        line: &str,
+        accept_version: crate::utils::AcceptVersion,
+    ) -> crate::Result<bool> {
+        let json = nojson::RawJson::parse(line)?;
+        let request = crate::utils::validate_json_rpc_request(json.value(), accept_version)?;
+        self.record(Direction::Outgoing, line)?;
+        self.summary.count_request(request.id.is_some());
+        if let Some(id) = request.id {
+            let seqno = self.summary.requests;
+            self.outstanding.insert(id.as_raw_str().to_owned(), seqno);
+        }
+        if let (Some(ordered), Some(id)) = (&mut self.ordered, request.id) {
+            ordered.record_request(id);
+        }
+        if let (Some(groups), Some(id)) = (&mut self.groups, request.id) {
+            groups
+                .methods
+                .entry(id.as_raw_str().to_owned())
+                .or_default()
+                .push_back(request.method.into_owned());
+        }
+        Ok(request.id.is_some())
+    }
+
+    fn tee_record(&mut self, direction: Direction, data: &[u8]) -> crate::Result<()> {
+        if let Some(tee) = &mut self.tee {
+            tee.record(direction, data)?;
+        }
+        Ok(())
+    }
+}
+
+// `--assert POINTER=VALUE`
+#[derive(Debug)]
+struct Assertion {
+    pointer: String,
+    expected: nojson::RawJsonOwned,
+}
+
+impl Assertion {
+    fn check(&self, response: nojson::RawJsonValue<'_, '_>) -> Result<(), String> {
+        let expected = self.expected.value();
+        match crate::utils::resolve_json_pointer(response, &self.pointer) {
+            Some(actual) if crate::utils::json_values_equal(actual, expected) => Ok(()),
+            Some(actual) => Err(format!(
+                "expected {}, got {}",
+                expected.as_raw_str(),
+                actual.as_raw_str()
+            )),
+            None => Err(format!("expected {}, got nothing", expected.as_raw_str())),
+        }
+    }
+}
+
+impl std::str::FromStr for Assertion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((pointer, value)) = s.split_once('=') else {
+            return Err(format!("assertion {s:?} must be in the form POINTER=VALUE"));
+        };
+        if !pointer.is_empty() && !pointer.starts_with('/') {
+            return Err(format!("JSON Pointer {pointer:?} must start with '/'"));
+        }
+        Ok(Self {
+            pointer: pointer.to_owned(),
+            expected: parse_expected_value(value)?,
+        })
+    }
+}
+
+// `--filter POINTER[=VALUE | =present]`
+#[derive(Debug)]
+struct ResponseFilter {
+    pointer: String,
+    expected: Option<nojson::RawJsonOwned>,
+}
+
+impl ResponseFilter {
+    fn matches(&self, response: nojson::RawJsonValue<'_, '_>) -> bool {
+        let Some(actual) = crate::utils::resolve_json_pointer(response, &self.pointer) else {
+            return false;
+        };
+        self.expected
+            .as_ref()
+            .is_none_or(|expected| crate::utils::json_values_equal(actual, expected.value()))
+    }
+}
+
+impl std::str::FromStr for ResponseFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pointer, value) = s.split_once('=').unwrap_or((s, "present"));
+        if !pointer.is_empty() && !pointer.starts_with('/') {
+            return Err(format!("JSON Pointer {pointer:?} must start with '/'"));
+        }
+        let expected = match value {
+            "present" => None,
+            value => Some(parse_expected_value(value)?),
+        };
+        Ok(Self {
+            pointer: pointer.to_owned(),
+            expected,
+        })
+    }
+}
+
+// `--set POINTER=VALUE`
+#[derive(Debug)]
+struct RequestOverride {
+    pointer: String,
+    value: nojson::RawJsonOwned,
+}
+
+impl std::str::FromStr for RequestOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((pointer, value)) = s.split_once('=') else {
+            return Err(format!("override {s:?} must be in the form POINTER=VALUE"));
+        };
+        if !pointer.starts_with('/') {
+            return Err(format!("JSON Pointer {pointer:?} must start with '/'"));
+        }
+        Ok(Self {
+            pointer: pointer.to_owned(),
+            value: parse_expected_value(value)?,
+        })
+    }
+}
+
+// `--only-id`
+#[derive(Debug)]
+struct IdSelection {
+    ids: Vec<nojson::RawJsonOwned>,
+    matched: Vec<bool>,
+    skipped: usize,
+}
+
+impl IdSelection {
+    fn new(ids: &[nojson::RawJsonOwned]) -> Self {
+        Self {
+            ids: ids.to_vec(),
+            matched: vec![false; ids.len()],
+            skipped: 0,
+        }
+    }
+}
+
+// Parses `value` as JSON, falling back to a JSON string holding `value` as is
+fn parse_expected_value(value: &str) -> Result<nojson::RawJsonOwned, String> {
+    nojson::RawJsonOwned::parse(value)
+        .or_else(|_| nojson::RawJsonOwned::parse(nojson::Json(value).to_string()))
+        .map_err(|e| e.to_string())
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum OutputOrder {
+    #[default]
+    Arrival,
+    Id,
+    Preserve,
+}
+
+impl std::str::FromStr for OutputOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "arrival" => Ok(OutputOrder::Arrival),
+            "id" => Ok(OutputOrder::Id),
+            "preserve" => Ok(OutputOrder::Preserve),
+            _ => Err(format!(
+                "unknown order {s:?} (expected \"arrival\", \"id\" or \"preserve\")"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    #[default]
+    Json,
+    #[cfg(feature = "msgpack")]
+    Msgpack,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            #[cfg(feature = "msgpack")]
+            "msgpack" => Ok(OutputFormat::Msgpack),
+            #[cfg(not(feature = "msgpack"))]
+            "msgpack" => {
+                Err("msgpack requires building with the `msgpack` cargo feature".to_owned())
+            }
+            _ => Err(format!(
+                "unknown output format {s:?} (expected \"json\" or \"msgpack\")"
+            )),
+        }
+    }
+}
+
+// Buffers responses for `--order id | preserve`
+#[derive(Debug)]
+struct OrderedResponses {
+    order: OutputOrder,
+    // Request id (raw JSON) => positions of the pending requests with that id
+    request_positions: std::collections::HashMap<String, std::collections::VecDeque<usize>>,
+    requests: usize,
+    responses: Vec<(ResponseKey, String)>,
+}
+
+// Integer ids are sorted numerically and precede string ids;
+// responses that cannot be associated with a request come last
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum ResponseKey {
+    Position(usize),
+    Integer(i64),
+    String(String),
+    Unknown,
+}
+
+impl OrderedResponses {
+    fn new(order: OutputOrder) -> Self {
+        Self {
+            order,
+            request_positions: std::collections::HashMap::new(),
+            requests: 0,
+            responses: Vec::new(),
+        }
+    }
+
+    fn record_request(&mut self, id: nojson::RawJsonValue<'_, '_>) {
+        if self.order == OutputOrder::Preserve {
+            self.request_positions
+                .entry(id.as_raw_str().to_owned())
+                .or_default()
+                .push_back(self.requests);
+        }
+        self.requests += 1;
+    }
+
+    fn push_response(&mut self, line: &str) -> crate::Result<()> {
+        let json = nojson::RawJson::parse(line)?;
+        let id = json.value().to_member("id").ok().and_then(|m| m.get());
+        let key = match (self.order, id) {
+            (OutputOrder::Preserve, Some(id)) => self
+                .request_positions
+                .get_mut(id.as_raw_str())
+                .and_then(|positions| positions.pop_front())
+                .map_or(ResponseKey::Unknown, ResponseKey::Position),
+            (_, Some(id)) if id.kind() == nojson::JsonValueKind::Integer => id
+                .as_raw_str()
+                .parse()
+                .map_or(ResponseKey::Unknown, ResponseKey::Integer),
+            (_, Some(id)) if id.kind() == nojson::JsonValueKind::String => {
+                ResponseKey::String(id.to_unquoted_string_str()?.into_owned())
+            }
+            _ => ResponseKey::Unknown,
+        };
+        self.responses.push((key, line.to_owned()));
+        Ok(())
+    }
+
+    fn into_sorted(mut self) -> impl Iterator<Item = String> {
+        self.responses.sort_by(|a, b| a.0.cmp(&b.0)); // Stable, so ties keep the arrival order
+        self.responses.into_iter().map(|(_, line)| line)
+    }
+}
+
+// Writes `--record` files in the following JSON Lines format:
+//
+// ```text
+// {"request":{"jsonrpc":"2.0","method":"foo","id":0}}
+// {"response":{"jsonrpc":"2.0","id":0,"result":"bar"}}
+// ```
+#[derive(Debug)]
+struct Recorder {
+    writer: std::io::BufWriter<std::fs::File>,
+}
+
+impl Recorder {
+    fn create(path: &std::path::Path) -> crate::Result<Self> {
+        let file = std::fs::File::create(path)
+            .map_err(|e| crate::Error::new(format!("failed to create {}: {e}", path.display())))?;
+        Ok(Self {
+            writer: std::io::BufWriter::new(file),
+        })
+    }
+
+    fn record(&mut self, direction: Direction, message: &str) -> crate::Result<()> {
+        let json = nojson::RawJson::parse(message)?;
+        let name = match direction {
+            Direction::Outgoing => "request",
+            Direction::Incoming => "response",
+        };
+        writeln!(
+            self.writer,
+            "{}",
+            nojson::object(|f| f.member(name, json.value()))
+        )?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// `--replay PATH`
+#[derive(Debug)]
+struct Replay {
+    requests: Vec<String>,
+    // Response id (raw JSON) => recorded responses with that id
+    responses: std::collections::HashMap<String, std::collections::VecDeque<String>>,
+    mismatches: usize,
+}
+
+impl Replay {
+    fn load(path: &std::path::Path) -> crate::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| crate::Error::new(format!("failed to read {}: {e}", path.display())))?;
+        let mut replay = Self {
+            requests: Vec::new(),
+            responses: std::collections::HashMap::new(),
+            mismatches: 0,
+        };
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let json = nojson::RawJson::parse(line)?;
+            if let Some(request) = json.value().to_member("request")?.get() {
+                replay
+                    .requests
+                    .push(nojson::json(|f| f.value(request)).to_string());
+            } else if let Some(response) = json.value().to_member("response")?.get() {
+                replay
+                    .responses
+                    .entry(response_id(response).to_owned())
+                    .or_default()
+                    .push_back(nojson::json(|f| f.value(response)).to_string());
+            } else {
+                return Err(json
+                    .value()
+                    .invalid("record must have a \"request\" or \"response\" member")
+                    .into());
+            }
+        }
+        Ok(replay)
+    }
+
+    fn compare(&mut self, line: &str, verbosity: crate::Verbosity) -> crate::Result<()> {
+        let json = nojson::RawJson::parse(line)?;
+        let id = response_id(json.value());
+        let Some(recorded) = self.responses.get_mut(id).and_then(|r| r.pop_front()) else {
+            verbosity.warn(format_args!(
+                "response for id {id} is not in the recording: {line}"
+            ));
+            self.mismatches += 1;
+            return Ok(());
+        };
+        let recorded_json = nojson::RawJson::parse(&recorded)?;
+        if !crate::utils::json_values_equal(json.value(), recorded_json.value()) {
+            verbosity.warn(format_args!(
+                "response for id {id} differs:\n  recorded: {recorded}\n  received: {line}"
+            ));
+            self.mismatches += 1;
+        }
+        Ok(())
+    }
+}
+
+// Returns the id (raw JSON; "null" if absent) of a well-formed response
+//
+// A response must have exactly one of "result" and "error"; `strict` (`--strict-responses`)
+// additionally rejects a null "result" and an "error" that is not {"code":INTEGER,"message":STRING}.
+fn validate_response_and_parse_id<'text>(
+    response: nojson::RawJsonValue<'text, '_>,
+    strict: bool,
+) -> Result<&'text str, InvalidResponse<'text>> {
+    let id = response_id(response);
+    let invalid = |reason: &str| InvalidResponse {
+        id,
+        reason: reason.to_owned(),
+    };
+    if response.kind() != nojson::JsonValueKind::Object {
+        return Err(invalid("response must be a JSON object"));
+    }
+    fn member<'text, 'raw>(
+        value: nojson::RawJsonValue<'text, 'raw>,
+        name: &str,
+    ) -> Option<nojson::RawJsonValue<'text, 'raw>> {
+        value.to_member(name).ok().and_then(|m| m.get())
+    }
+    match (member(response, "result"), member(response, "error")) {
+        (Some(_), Some(_)) => Err(invalid("response must not contain both result and error")),
+        (None, None) => Err(invalid("response has neither result nor error")),
+        (Some(result), None) if strict && result.kind() == nojson::JsonValueKind::Null => {
+            Err(invalid("result is null"))
+        }
+        (None, Some(error)) if strict => {
+            if error.kind() != nojson::JsonValueKind::Object {
+                Err(invalid("error must be an object"))
+            } else if member(error, "code")
+                .is_none_or(|c| c.kind() != nojson::JsonValueKind::Integer)
+            {
+                Err(invalid("error code must be an integer"))
+            } else if member(error, "message")
+                .is_none_or(|m| m.kind() != nojson::JsonValueKind::String)
+            {
+                Err(invalid("error message must be a string"))
+            } else {
+                Ok(id)
+            }
+        }
+        _ => Ok(id),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct InvalidResponse<'text> {
+    // Raw JSON
+    id: &'text str,
+    reason: String,
+}
+
+impl std::fmt::Display for InvalidResponse<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid response for request id {}: {}",
+            self.id, self.reason
+        )
+    }
+}
+
+fn response_id<'text>(response: nojson::RawJsonValue<'text, '_>) -> &'text str {
+    response
+        .to_member("id")
+        .ok()
+        .and_then(|m| m.get())
+        .map_or("null", |id| id.as_raw_str())
+}
+
+// `--table`
+#[derive(Debug, Default)]
+struct ResponseTable {
+    rows: Vec<[String; 3]>,
+}
+
+impl ResponseTable {
+    const HEADER: [&str; 3] = ["ID", "STATUS", "VALUE"];
+
+    fn push_response(&mut self, line: &str, width: usize) -> crate::Result<()> {
+        let json = nojson::RawJson::parse(line)?;
+        let member = |name| json.value().to_member(name).ok().and_then(|m| m.get());
+        let id = member("id").map_or("-", |id| id.as_raw_str()).to_owned();
+        let (status, value) = if let Some(result) = member("result") {
+            ("result", nojson::json(|f| f.value(result)).to_string())
+        } else if let Some(error) = member("error") {
+            let code = error.to_member("code").ok().and_then(|m| m.get());
+            let message = error.to_member("message").ok().and_then(|m| m.get());
+            let value = match (code, message) {
+                (Some(code), Some(message)) => {
+                    format!(
+                        "{} {}",
+                        code.as_raw_str(),
+                        message.to_unquoted_string_str()?
+                    )
+                }
+                _ => nojson::json(|f| f.value(error)).to_string(),
+            };
+            ("error", value)
+        } else {
+            ("?", nojson::json(|f| f.value(json.value())).to_string())
+        };
+        self.rows
+            .push([id, status.to_owned(), truncate_chars(&value, width)]);
+        Ok(())
+    }
+
+    fn write<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let mut widths = Self::HEADER.map(|h| h.len());
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        let header = Self::HEADER.map(|h| h.to_owned());
+        for row in std::iter::once(&header).chain(&self.rows) {
+            let line = format!(
+                "{:<w0$}  {:<w1$}  {}",
+                row[0],
+                row[1],
+                row[2],
+                w0 = widths[0],
+                w1 = widths[1]
+            );
+            if !crate::utils::write_line(writer, line.trim_end())? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn truncate_chars(s: &str, max: usize) -> String {
+    if s.chars().count() <= max {
+        return s.to_owned();
+    }
+    let mut truncated: String = s.chars().take(max.saturating_sub(3)).collect();
+    truncated.push_str("...");
+    truncated
+}
+
+// `--truncate-value CHARS` (object member names are kept as is)
+#[derive(Debug, Clone, Copy)]
+struct TruncatedStrings<'text, 'raw>(nojson::RawJsonValue<'text, 'raw>, usize);
+
+impl nojson::DisplayJson for TruncatedStrings<'_, '_> {
+    fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
+        let Self(value, max) = *self;
+        match value.kind() {
+            nojson::JsonValueKind::Object => {
+                let members = value
+                    .to_object()
+                    .map_err(|_| std::fmt::Error)?
+                    .map(|(name, value)| Ok((name.to_unquoted_string_str()?, value)))
+                    .collect::<Result<Vec<_>, nojson::JsonParseError>>()
+                    .map_err(|_| std::fmt::Error)?;
+                f.object(|f| {
+                    f.members(
+                        members
+                            .into_iter()
+                            .map(|(n, v)| (n, TruncatedStrings(v, max))),
+                    )
+                })
+            }
+            nojson::JsonValueKind::Array => {
+                let elements = value.to_array().map_err(|_| std::fmt::Error)?;
+                f.array(|f| f.elements(elements.map(|v| TruncatedStrings(v, max))))
+            }
+            nojson::JsonValueKind::String => {
+                let s = value
+                    .to_unquoted_string_str()
+                    .map_err(|_| std::fmt::Error)?;
+                if s.chars().count() <= max {
+                    return f.value(value);
+                }
+                let mut truncated: String = s.chars().take(max).collect();
+                truncated.push('…');
+                f.value(truncated)
+            }
+            _ => f.value(value),
+        }
+    }
+}
+
+// `--group-by-method DIR`
+#[derive(Debug)]
+struct MethodGroups {
+    dir: std::path::PathBuf,
+    // Request id (raw JSON) => methods of the pending requests with that id
+    methods: std::collections::HashMap<String, std::collections::VecDeque<String>>,
+    files: std::collections::HashMap<String, std::io::BufWriter<std::fs::File>>,
+}
+
+impl MethodGroups {
+    fn new(dir: std::path::PathBuf) -> Self {
+        Self {
+            dir,
+            methods: std::collections::HashMap::new(),
+            files: std::collections::HashMap::new(),
+        }
+    }
+
+    fn writer_for(
+        &mut self,
+        response: &str,
+    ) -> crate::Result<&mut std::io::BufWriter<std::fs::File>> {
+        let json = nojson::RawJson::parse(response)?;
+        let method = json
+            .value()
+            .to_member("id")
+            .ok()
+            .and_then(|m| m.get())
+            .and_then(|id| self.methods.get_mut(id.as_raw_str()))
+            .and_then(|methods| methods.pop_front());
+        let name = match method {
+            Some(method) => sanitize_file_name(&method),
+            None => "_unknown".to_owned(),
+        };
+        match self.files.entry(name) {
+            std::collections::hash_map::Entry::Occupied(entry) => Ok(entry.into_mut()),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                std::fs::create_dir_all(&self.dir).map_err(|e| {
+                    crate::Error::new(format!("failed to create {}: {e}", self.dir.display()))
+                })?;
+                let path = self.dir.join(format!("{}.jsonl", entry.key()));
+                let file = std::fs::File::create(&path).map_err(|e| {
+                    crate::Error::new(format!("failed to create {}: {e}", path.display()))
+                })?;
+                Ok(entry.insert(std::io::BufWriter::new(file)))
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.files.values_mut().try_for_each(|file| file.flush())
+    }
+}
+
+// Replaces characters that are unsafe in file names (e.g. '/') with '_'
+fn sanitize_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.is_empty() || name.starts_with('.') {
+        format!("_{name}")
+    } else {
+        name
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Outgoing,
+    Incoming,
+}
+
+// Writes raw messages in the following format:
+//
+// ```text
+// > 1700000000.123456 37 bytes
+// 00000000  7b 22 6a 73 6f 6e 72 70  63 22 3a 22 32 2e 30 22  |{"jsonrpc":"2.0"|
+// ...
+// ```
+#[derive(Debug)]
+struct Tee {
+    writer: std::io::BufWriter<std::fs::File>,
+}
+
+impl Tee {
+    fn create(path: &std::path::Path) -> crate::Result<Self> {
+        let file = std::fs::File::create(path)
+            .map_err(|e| crate::Error::new(format!("failed to create {}: {e}", path.display())))?;
+        Ok(Self {
+            writer: std::io::BufWriter::new(file),
+        })
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) -> std::io::Result<()> {
+        let marker = match direction {
+            Direction::Outgoing => '>',
+            Direction::Incoming => '<',
+        };
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        writeln!(
+            self.writer,
+            "{marker} {}.{:06} {} bytes",
+            timestamp.as_secs(),
+            timestamp.subsec_micros(),
+            data.len()
+        )?;
+        for (i, chunk) in data.chunks(16).enumerate() {
+            write!(self.writer, "{:08x} ", i * 16)?;
+            for j in 0..16 {
+                if j == 8 {
+                    write!(self.writer, " ")?;
+                }
+                match chunk.get(j) {
+                    Some(b) => write!(self.writer, " {b:02x}")?,
+                    None => write!(self.writer, "   ")?,
+                }
+            }
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            writeln!(self.writer, "  |{ascii}|")?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[derive(Debug, Default)]
+struct CallSummary {
+    requests: usize,
+    notifications: usize,
+    responses: usize,
+    packets_sent: usize,
+    packets_received: usize,
+    bytes_sent: usize,
+    bytes_received: usize,
+    elapsed: Duration,
+}
+
+impl CallSummary {
+    fn count_request(&mut self, has_id: bool) {
+        if has_id {
+            self.requests += 1;
+        } else {
+            self.notifications += 1;
+        }
+    }
+}
+
+impl nojson::DisplayJson for CallSummary {
+    fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
+        f.object(|f| {
+            f.member("requests", self.requests)?;
+            f.member("notifications", self.notifications)?;
+            f.member("responses", self.responses)?;
+            f.member("packets_sent", self.packets_sent)?;
+            f.member("packets_received", self.packets_received)?;
+            f.member("bytes_sent", self.bytes_sent)?;
+            f.member("bytes_received", self.bytes_received)?;
+            f.member("elapsed_secs", self.elapsed.as_secs_f64())
+        })
+    }
+}
+
+// Gives the server process up to `timeout` to exit after its stdin is closed, then kills it
+// (returning `Ok(None)` in that case)
+fn wait_child_exit(
+    child: &mut std::process::Child,
+    timeout: Duration,
+) -> crate::Result<Option<std::process::ExitStatus>> {
+    let deadline = std::time::Instant::now() + timeout;
+    while timeout.is_zero() || std::time::Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    child.kill()?;
+    child.wait()?;
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(response: &str, strict: bool) -> Result<String, String> {
+        let json = nojson::RawJson::parse(response).expect("valid JSON");
+        validate_response_and_parse_id(json.value(), strict)
+            .map(|id| id.to_owned())
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn response_with_both_result_and_error_is_rejected() {
+        assert_eq!(
+            validate(
+                r#"{"jsonrpc":"2.0","id":0,"result":1,"error":{"code":-32603,"message":"x"}}"#,
+                false
+            ),
+            Err(
+                "invalid response for request id 0: response must not contain both result and error"
+                    .to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn well_formed_response_id_is_parsed() {
+        assert_eq!(
+            validate(r#"{"jsonrpc":"2.0","id":"a","result":1}"#, true),
+            Ok(r#""a""#.to_owned())
+        );
+    }
+
+    #[test]
+    fn response_with_neither_result_nor_error_is_rejected() {
+        assert_eq!(
+            validate(r#"{"jsonrpc":"2.0","id":1}"#, false),
+            Err(
+                "invalid response for request id 1: response has neither result nor error"
+                    .to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn null_result_is_rejected_only_if_strict() {
+        let response = r#"{"jsonrpc":"2.0","id":2,"result":null}"#;
+        assert_eq!(validate(response, false), Ok("2".to_owned()));
+        assert_eq!(
+            validate(response, true),
+            Err("invalid response for request id 2: result is null".to_owned())
+        );
+    }
+
+    #[test]
+    fn malformed_error_is_rejected_only_if_strict() {
+        let cases = [
+            (r#""oops""#, "error must be an object"),
+            (r#"{"message":"x"}"#, "error code must be an integer"),
+            (
+                r#"{"code":"a","message":"x"}"#,
+                "error code must be an integer",
+            ),
+            (r#"{"code":-32603}"#, "error message must be a string"),
+        ];
+        for (error, reason) in cases {
+            let response = format!(r#"{{"jsonrpc":"2.0","id":3,"error":{error}}}"#);
+            assert_eq!(validate(&response, false), Ok("3".to_owned()));
+            assert_eq!(
+                validate(&response, true),
+                Err(format!("invalid response for request id 3: {reason}"))
+            );
         }
+        assert_eq!(
+            validate(
+                r#"{"jsonrpc":"2.0","id":3,"error":{"code":-32603,"message":"x"}}"#,
+                true
+            ),
+            Ok("3".to_owned())
+        );
     }
-    Ok(())
 }
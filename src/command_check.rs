@@ -0,0 +1,185 @@
+use std::time::Duration;
+
+pub fn try_run(args: &mut noargs::RawArgs, verbosity: crate::Verbosity) -> noargs::Result<bool> {
+    if !noargs::cmd("check")
+        .doc(concat!(
+            "Check whether a JSON-RPC server is responding\n",
+            "\n",
+            "This command sends a request (or --samples requests) and exits with status 0\n",
+            "if enough responses arrive within the timeout, or with a non-zero status otherwise."
+        ))
+        .take(args)
+        .is_present()
+    {
+        return Ok(false);
+    }
+
+    let method: String = noargs::opt("method")
+        .short('m')
+        .ty("NAME")
+        .doc("Method name of the request")
+        .default("ping")
+        .take(args)
+        .then(|o| o.value().parse())?;
+    let timeout: Duration = noargs::opt("timeout")
+        .ty("SECONDS")
+        .doc("Time to wait for the response")
+        .default("1")
+        .take(args)
+        .then(|o| crate::utils::parse_duration_secs(o.value()))?;
+    let samples: std::num::NonZeroUsize = noargs::opt("samples")
+        .ty("COUNT")
+        .doc("Number of requests sent (one after another) per check")
+        .default("1")
+        .take(args)
+        .then(|o| o.value().parse())?;
+    let require: std::num::NonZeroUsize = noargs::opt("require")
+        .ty("COUNT")
+        .doc("Number of responses to --samples requests required for the check to succeed")
+        .default("1")
+        .take(args)
+        .then(|o| o.value().parse())?;
+    let interval: Option<Duration> = noargs::opt("interval")
+        .ty("SECONDS")
+        .doc(concat!(
+            "Keep probing the server every SECONDS until interrupted, printing a JSON line\n",
+            "to stdout whenever it goes up or down (each probe is logged with --verbose)"
+        ))
+        .take(args)
+        .present_and_then(|o| crate::utils::parse_duration_secs(o.value()))?;
+    let server_addr: std::net::SocketAddr = noargs::arg("<SERVER>")
+        .doc("JSON-RPC server address or hostname")
+        .example("127.0.0.1:8080")
+        .take(args)
+        .then(|a| crate::utils::parse_socket_addr(a.value()))?;
+
+    if args.metadata().help_mode {
+        return Ok(true);
+    }
+    if timeout.is_zero() {
+        return Err(noargs::Error::other(
+            args,
+            "--timeout must be greater than 0",
+        ));
+    }
+    if require > samples {
+        return Err(noargs::Error::other(
+            args,
+            "--require must not exceed --samples",
+        ));
+    }
+
+    let command = CheckCommand {
+        server_addr,
+        method,
+        timeout,
+        samples: samples.get(),
+        require: require.get(),
+        verbosity,
+    };
+    if let Some(interval) = interval {
+        command.monitor(interval)?;
+        return Ok(true);
+    }
+    if let Err(e) = command.run() {
+        // Only the exit status is reported unless `--verbose` is specified
+        verbosity.info(format_args!("check failed: {}", e.reason));
+        std::process::exit(1);
+    }
+    Ok(true)
+}
+
+struct CheckCommand {
+    server_addr: std::net::SocketAddr,
+    method: String,
+    timeout: Duration,
+    samples: usize,
+    require: usize,
+    verbosity: crate::Verbosity,
+}
+
+impl CheckCommand {
+    // Stops probing as soon as `--require` responses have arrived
+    fn run(&self) -> crate::Result<()> {
+        let mut successes = 0;
+        for i in 0..self.samples {
+            match self.probe() {
+                Ok(()) => successes += 1,
+                Err(e) if self.samples == 1 => return Err(e),
+                Err(e) => {
+                    self.verbosity
+                        .debug(format_args!("probe #{} failed: {}", i + 1, e.reason))
+                }
+            }
+            if successes >= self.require {
+                return Ok(());
+            }
+        }
+        Err(crate::Error::new(format!(
+            "only {successes} of {} probes were answered ({} required)",
+            self.samples, self.require
+        )))
+    }
+
+    fn probe(&self) -> crate::Result<()> {
+        let socket = crate::command_call::connect_to_server_udp(self.server_addr, None, None)?;
+        socket.set_read_timeout(Some(self.timeout))?;
+
+        let start_time = std::time::Instant::now();
+        let request = crate::command_req::request_json(&self.method, None, Some(0)).to_string();
+        socket.send(request.as_bytes())?;
+
+        let mut recv_buf = vec![0u8; 65507];
+        let size = match socket.recv(&mut recv_buf) {
+            Ok(size) => size,
+            Err(e)
+                if e.kind() == std::io::ErrorKind::WouldBlock
+                    || e.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                return Err(crate::Error::new(format!(
+                    "no response from {} within {:?}",
+                    self.server_addr, self.timeout
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        self.verbosity.info(format_args!(
+            "received {size} bytes from {} after {:?}",
+            self.server_addr,
+            start_time.elapsed()
+        ));
+        Ok(())
+    }
+
+    // Only returns on errors writing to stdout (or once stdout is closed)
+    fn monitor(&self, interval: Duration) -> crate::Result<()> {
+        let mut stdout = std::io::stdout().lock();
+        let mut last_up = None;
+        loop {
+            let start_time = std::time::Instant::now();
+            let result = self.run();
+            if let Err(e) = &result {
+                self.verbosity
+                    .info(format_args!("check failed: {}", e.reason));
+            }
+
+            let up = result.is_ok();
+            if last_up != Some(up) {
+                let timestamp = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs_f64();
+                let line = nojson::object(|f| {
+                    f.member("timestamp", timestamp)?;
+                    f.member("server", self.server_addr.to_string())?;
+                    f.member("state", if up { "up" } else { "down" })
+                });
+                if !crate::utils::write_line(&mut stdout, line)? {
+                    return Ok(());
+                }
+                last_up = Some(up);
+            }
+            std::thread::sleep(interval.saturating_sub(start_time.elapsed()));
+        }
+    }
+}
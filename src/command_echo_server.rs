@@ -1,6 +1,8 @@
+use std::collections::BTreeSet;
+
 const MAX_UDP_PACKET: usize = 65507;
 
-pub fn try_run(args: &mut noargs::RawArgs) -> noargs::Result<bool> {
+pub fn try_run(args: &mut noargs::RawArgs, verbosity: crate::Verbosity) -> noargs::Result<bool> {
     if !noargs::cmd("echo-server")
         .doc(concat!(
             "Run a JSON-RPC echo server\n",
@@ -15,22 +17,207 @@ pub fn try_run(args: &mut noargs::RawArgs) -> noargs::Result<bool> {
     }
 
     let bind_addr = noargs::arg("<ADDR>")
-        .doc("UDP bind address (FORMAT: `[IP_ADDR]:PORT`)")
+        .doc("UDP bind address (FORMAT: `[IP_ADDR | HOSTNAME]:PORT`, defaults to 127.0.0.1)")
         .example(":9000")
         .take(args)
         .then(|a| crate::utils::parse_socket_addr(a.value()))?;
+    let bind_device: Option<String> = noargs::opt("bind-device")
+        .ty("INTERFACE")
+        .doc("Send and receive only through the network interface INTERFACE (Linux only; SO_BINDTODEVICE)")
+        .example("eth0")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
     let send_buf_size: std::num::NonZeroUsize = noargs::opt("send-buf-size")
         .short('b')
         .ty("BYTES")
-        .doc("Max UDP payload per response packet; responses are joined with '\\n' up to this size")
+        .doc("Max UDP payload per response packet; responses are packed up to this size")
         .default("1200")
         .take(args)
         .then(|o| o.value().parse())?;
+    let one_per_packet: bool = noargs::flag("one-per-packet")
+        .doc("Send each response in its own UDP packet instead of packing them up to --send-buf-size")
+        .take(args)
+        .is_present();
+    let strict_methods: bool = noargs::flag("strict-methods")
+        .doc("Reject requests calling methods reserved by the spec (\"rpc.\" prefix) with error -32600")
+        .take(args)
+        .is_present();
+    let allow_methods: BTreeSet<String> = crate::utils::take_repeated_opt(
+        noargs::opt("allow-method")
+            .ty("NAME")
+            .doc("Only echo requests calling NAME; others get error -32601 (repeatable)"),
+        args,
+        |o| o.value().parse(),
+    )?
+    .into_iter()
+    .collect();
+    let deny_methods: BTreeSet<String> = crate::utils::take_repeated_opt(
+        noargs::opt("deny-method")
+            .ty("NAME")
+            .doc("Reply error -32601 to requests calling NAME (repeatable)"),
+        args,
+        |o| o.value().parse(),
+    )?
+    .into_iter()
+    .collect();
+    let list_method: Option<String> = noargs::opt("list-method")
+        .ty("NAME")
+        .doc(concat!(
+            "Answer requests calling NAME with the method configuration instead of echoing them\n",
+            "(the result is {\"allow_methods\": [...], \"deny_methods\": [...], \"strict_methods\": BOOL})"
+        ))
+        .example("rpc.discover")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let result_key: Option<String> = noargs::opt("result-key")
+        .ty("NAME")
+        .doc("Wrap the echoed request in an object as {\"result\": {NAME: <request>}}")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let response_template: Option<String> = noargs::opt("response-template")
+        .ty("TEMPLATE")
+        .doc(concat!(
+            "Reply with TEMPLATE instead of the standard echo response\n",
+            "(`%i` is replaced by the request id, `%r` by the request object and `%%` by '%')"
+        ))
+        .example("{\"jsonrpc\":\"2.0\",\"id\":%i,\"result\":{\"ok\":true,\"echo\":%r}}")
+        .take(args)
+        .present_and_then(|o| {
+            let template = o.value().to_owned();
+            nojson::RawJson::parse(&render_response_template(&template, "0", "{}"))
+                .map_err(|e| format!("template does not produce valid JSON: {e}"))?;
+            Ok::<_, String>(template)
+        })?;
+    let error_data: Option<nojson::RawJsonOwned> = noargs::opt("error-data")
+        .ty("JSON")
+        .doc("Attach JSON as the \"data\" member of the errors of rejected methods")
+        .example("{\"detail\":\"rejected\"}")
+        .take(args)
+        .present_and_then(|o| nojson::RawJsonOwned::parse(o.value()))?;
+    let silent_invalid: bool = noargs::flag("silent-invalid")
+        .doc("Silently drop malformed input instead of replying with -32700 / -32600 errors")
+        .take(args)
+        .is_present();
+    let run_for: Option<std::time::Duration> = noargs::opt("run-for")
+        .ty("SECONDS")
+        .doc("Exit cleanly after running for the given wall-clock time")
+        .take(args)
+        .present_and_then(|o| crate::utils::parse_duration_secs(o.value()))?;
+    let limit_bps: Option<std::num::NonZeroU64> = noargs::opt("limit-bps")
+        .ty("BYTES_PER_SEC")
+        .doc("Throttle outgoing response bytes to approximately this rate")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let delay: Option<std::time::Duration> = noargs::opt("delay-ms")
+        .ty("MILLISECONDS")
+        .doc("Delay responses (or the --delay-rate fraction of them) by MILLISECONDS")
+        .take(args)
+        .present_and_then(|o| o.value().parse().map(std::time::Duration::from_millis))?;
+    let first_delay: Option<std::time::Duration> = noargs::opt("first-delay-ms")
+        .ty("MILLISECONDS")
+        .doc("Delay only the first response after startup by MILLISECONDS (simulates a cold start)")
+        .take(args)
+        .present_and_then(|o| o.value().parse().map(std::time::Duration::from_millis))?;
+    let delay_rate: f64 = noargs::opt("delay-rate")
+        .ty("RATE")
+        .doc("Fraction (0.0 to 1.0) of responses randomly delayed by --delay-ms, to simulate tail latency")
+        .default("1")
+        .take(args)
+        .then(|o| {
+            let rate: f64 = o.value().parse().map_err(|e| format!("{e}"))?;
+            if !(0.0..=1.0).contains(&rate) {
+                return Err("must be between 0.0 and 1.0".to_owned());
+            }
+            Ok(rate)
+        })?;
+    let seed: Option<u64> = noargs::opt("seed")
+        .ty("INTEGER")
+        .doc("Seed of the random number generator (default: derived from the current time)")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let per_peer: bool = noargs::flag("per-peer")
+        .doc(concat!(
+            "Serve each peer address on its own thread (with its own --limit-bps budget)\n",
+            "(without --max-peers, threads are never reclaimed, so memory grows with the number of peers)"
+        ))
+        .take(args)
+        .is_present();
+    let max_peers: Option<std::num::NonZeroUsize> = noargs::opt("max-peers")
+        .ty("COUNT")
+        .doc(concat!(
+            "With --per-peer, reply error -32000 to packets from new peers while COUNT peers are served\n",
+            "(a peer stops counting once it has sent nothing for --peer-window)"
+        ))
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let peer_window: Option<std::time::Duration> = noargs::opt("peer-window")
+        .ty("SECONDS")
+        .doc("With --max-peers, forget peers (and stop their threads) after SECONDS of silence (default: 60)")
+        .take(args)
+        .present_and_then(|o| crate::utils::parse_duration_secs(o.value()))?;
+    let max_bytes: Option<usize> = noargs::opt("max-bytes")
+        .ty("BYTES")
+        .doc(concat!(
+            "Stop sending once responses totaling BYTES have been sent (requests are still received)\n",
+            "(the first packet that would exceed the budget is dropped, as are all later ones)"
+        ))
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let exit_on_max_bytes: bool = noargs::flag("exit-on-max-bytes")
+        .doc("Exit instead of going silent once the --max-bytes budget is exhausted")
+        .take(args)
+        .is_present();
+    let record: Option<std::path::PathBuf> = noargs::opt("record")
+        .ty("PATH")
+        .doc(concat!(
+            "Append every received packet to PATH as NDJSON\n",
+            "(peer address, timestamp, size, validity and data of each packet)"
+        ))
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let framing: crate::framing::Framing = noargs::opt("framing")
+        .ty("lines | content-length")
+        .doc("How multiple messages are delimited within a UDP packet")
+        .default("lines")
+        .take(args)
+        .then(|o| o.value().parse())?;
 
     if args.metadata().help_mode {
         return Ok(true);
     }
 
+    if max_peers.is_some() && !per_peer {
+        return Err(noargs::Error::other(
+            args,
+            "--max-peers requires --per-peer",
+        ));
+    }
+    if peer_window.is_some() && max_peers.is_none() {
+        return Err(noargs::Error::other(
+            args,
+            "--peer-window requires --max-peers",
+        ));
+    }
+    if framing == crate::framing::Framing::Lines
+        && response_template.as_ref().is_some_and(|t| t.contains('\n'))
+    {
+        return Err(noargs::Error::other(
+            args,
+            "--response-template must be a single line with --framing lines",
+        ));
+    }
+    if response_template.is_some() && result_key.is_some() {
+        return Err(noargs::Error::other(
+            args,
+            "--response-template and --result-key cannot be specified together",
+        ));
+    }
+    if exit_on_max_bytes && max_bytes.is_none() {
+        return Err(noargs::Error::other(
+            args,
+            "--exit-on-max-bytes requires --max-bytes",
+        ));
+    }
     if send_buf_size.get() > MAX_UDP_PACKET {
         return Err(noargs::Error::other(
             args,
@@ -38,98 +225,711 @@ pub fn try_run(args: &mut noargs::RawArgs) -> noargs::Result<bool> {
         ));
     }
 
-    run(bind_addr, send_buf_size.get())?;
+    let command = EchoServerCommand {
+        bind_addr,
+        bind_device,
+        send_buf_size: send_buf_size.get(),
+        one_per_packet,
+        framing,
+        handler: EchoHandler {
+            strict_methods,
+            allow_methods,
+            deny_methods,
+            list_method,
+            result_key,
+            response_template,
+            error_data,
+        },
+        silent_invalid,
+        run_for,
+        limit_bps,
+        delay,
+        delay_rate,
+        first_delay,
+        seed,
+        per_peer,
+        max_peers: max_peers.map(|n| n.get()),
+        peer_window: peer_window.unwrap_or(std::time::Duration::from_secs(60)),
+        max_bytes,
+        exit_on_max_bytes,
+        record,
+        verbosity,
+    };
+    command.run()?;
     Ok(true)
 }
 
-fn reply_err<M>(socket: &std::net::UdpSocket, addr: std::net::SocketAddr, code: i32, message: M)
+/// Socket-independent part of the echo server that turns a request message into a response.
+#[derive(Debug, Default, Clone)]
+pub struct EchoHandler {
+    /// Rejects methods beginning with `rpc.` with error -32600.
+    pub strict_methods: bool,
+
+    /// If not empty, methods not in this set are rejected with error -32601.
+    pub allow_methods: BTreeSet<String>,
+
+    /// Methods in this set are rejected with error -32601.
+    pub deny_methods: BTreeSet<String>,
+
+    /// If set, requests calling this method are answered with the method configuration
+    /// (regardless of the other method settings).
+    pub list_method: Option<String>,
+
+    /// If set, the echoed request is wrapped as `{"result": {KEY: <request>}}`.
+    pub result_key: Option<String>,
+
+    /// If set, responses are built from this template instead of echoing the request
+    /// (see [`render_response_template()`]).
+    pub response_template: Option<String>,
+
+    /// If set, included as the `data` member of the errors of rejected methods.
+    pub error_data: Option<nojson::RawJsonOwned>,
+}
+
+/// Outcome of [`EchoHandler::handle_request()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EchoOutcome {
+    /// The request was echoed back as the result of `response`.
+    Result {
+        id: nojson::RawJsonOwned,
+        response: String,
+    },
+
+    /// The request was rejected with the error `response`.
+    Error(String),
+
+    /// The message was not a valid JSON-RPC request (`response` is an error with a null id).
+    Invalid(String),
+
+    /// The request was a notification, so no response should be sent.
+    NoResponse,
+}
+
+impl EchoHandler {
+    pub fn handle_request(&self, message: &str) -> EchoOutcome {
+        let json = match nojson::RawJson::parse(message) {
+            Ok(json) => json,
+            Err(e) => return EchoOutcome::Invalid(error_response(None, -32700, e)),
+        };
+        let request =
+            match crate::utils::validate_json_rpc_request(json.value(), Default::default()) {
+                Ok(request) => request,
+                Err(e) => return EchoOutcome::Invalid(error_response(None, -32600, e)),
+            };
+        let Some(id) = request.id else {
+            return EchoOutcome::NoResponse;
+        };
+
+        if self.list_method.as_deref() == Some(request.method.as_ref()) {
+            let response = nojson::object(|f| {
+                f.member("jsonrpc", "2.0")?;
+                f.member("id", id)?;
+                f.member(
+                    "result",
+                    nojson::object(|f| {
+                        f.member("allow_methods", &self.allow_methods)?;
+                        f.member("deny_methods", &self.deny_methods)?;
+                        f.member("strict_methods", self.strict_methods)
+                    }),
+                )
+            })
+            .to_string();
+            return EchoOutcome::Result {
+                id: nojson::RawJsonOwned::try_from(id).expect("id is a valid JSON value"),
+                response,
+            };
+        }
+        if self.strict_methods && request.method.starts_with("rpc.") {
+            return EchoOutcome::Error(error_response_with_data(
+                Some(id),
+                -32600,
+                format_args!("method {:?} is reserved", request.method),
+                self.error_data.as_ref().map(|data| data.value()),
+            ));
+        }
+        if !self.is_method_allowed(&request.method) {
+            return EchoOutcome::Error(error_response_with_data(
+                Some(id),
+                -32601,
+                format_args!("method not found: {:?}", request.method),
+                self.error_data.as_ref().map(|data| data.value()),
+            ));
+        }
+
+        if let Some(template) = &self.response_template {
+            let response = render_response_template(template, id.as_raw_str(), message.trim());
+            if let Err(e) = nojson::RawJson::parse(&response) {
+                return EchoOutcome::Error(error_response(
+                    Some(id),
+                    -32603,
+                    format_args!("response template produced invalid JSON: {e}"),
+                ));
+            }
+            return EchoOutcome::Result {
+                id: nojson::RawJsonOwned::try_from(id).expect("id is a valid JSON value"),
+                response,
+            };
+        }
+        let response = nojson::object(|f| {
+            f.member("jsonrpc", "2.0")?;
+            f.member("id", id)?;
+            match &self.result_key {
+                Some(key) => f.member("result", nojson::object(|f| f.member(key, &json))),
+                None => f.member("result", &json),
+            }
+        })
+        .to_string();
+        EchoOutcome::Result {
+            id: nojson::RawJsonOwned::try_from(id).expect("id is a valid JSON value"),
+            response,
+        }
+    }
+
+    fn is_method_allowed(&self, method: &str) -> bool {
+        (self.allow_methods.is_empty() || self.allow_methods.contains(method))
+            && !self.deny_methods.contains(method)
+    }
+}
+
+/// Replaces `%i` in `template` with `id`, `%r` with `request` and `%%` with `%`.
+pub fn render_response_template(template: &str, id: &str, request: &str) -> String {
+    let mut rendered = String::with_capacity(template.len() + request.len());
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            rendered.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('i') => rendered.push_str(id),
+            Some('r') => rendered.push_str(request),
+            Some('%') => rendered.push('%'),
+            Some(c) => {
+                rendered.push('%');
+                rendered.push(c);
+            }
+            None => rendered.push('%'),
+        }
+    }
+    rendered
+}
+
+/// Builds a JSON-RPC error response (`None` id is serialized as null).
+pub fn error_response<M>(id: Option<nojson::RawJsonValue<'_, '_>>, code: i32, message: M) -> String
 where
     M: std::fmt::Display,
 {
-    let response = nojson::object(|f| {
+    error_response_with_data(id, code, message, None)
+}
+
+/// Same as [`error_response()`], but also includes `data` in the error object if it is `Some`.
+pub fn error_response_with_data<M>(
+    id: Option<nojson::RawJsonValue<'_, '_>>,
+    code: i32,
+    message: M,
+    data: Option<nojson::RawJsonValue<'_, '_>>,
+) -> String
+where
+    M: std::fmt::Display,
+{
+    nojson::object(|f| {
         f.member("jsonrpc", "2.0")?;
-        f.member("id", ())?; // null
+        f.member("id", id)?;
         f.member(
             "error",
             nojson::object(|f| {
                 f.member("code", code)?;
-                f.member("message", message.to_string())
+                f.member("message", message.to_string())?;
+                if let Some(data) = data {
+                    f.member("data", data)?;
+                }
+                Ok(())
             }),
         )
+    })
+    .to_string()
+}
+
+/// Starts an echo server with the default settings on a background thread.
+///
+/// Returns the bound address of the server, which runs until the process exits.
+pub(crate) fn spawn_echo_server(
+    bind_addr: std::net::SocketAddr,
+    framing: crate::framing::Framing,
+    verbosity: crate::Verbosity,
+) -> crate::Result<std::net::SocketAddr> {
+    let command = EchoServerCommand {
+        bind_addr,
+        bind_device: None,
+        send_buf_size: MAX_UDP_PACKET,
+        one_per_packet: false,
+        framing,
+        handler: EchoHandler::default(),
+        silent_invalid: false,
+        run_for: None,
+        limit_bps: None,
+        delay: None,
+        delay_rate: 1.0,
+        first_delay: None,
+        seed: None,
+        per_peer: false,
+        max_peers: None,
+        peer_window: std::time::Duration::from_secs(60),
+        max_bytes: None,
+        exit_on_max_bytes: false,
+        record: None,
+        verbosity,
+    };
+    let session = command.bind()?;
+    let local_addr = session.socket.local_addr()?;
+    std::thread::spawn(move || {
+        if let Err(e) = command.serve(session) {
+            verbosity.warn(format_args!("echo server failed: {e}"));
+        }
     });
-    let _ = socket.send_to(response.to_string().as_bytes(), addr); // Ignores the result for simplicity
+    Ok(local_addr)
 }
 
-fn run(bind_addr: std::net::SocketAddr, send_buf_size: usize) -> crate::Result<()> {
-    let socket = std::net::UdpSocket::bind(bind_addr)?;
-    let mut recv_buf = vec![0u8; MAX_UDP_PACKET];
-    let mut send_buf = vec![0u8; send_buf_size];
-    loop {
-        let (size, peer_addr) = socket.recv_from(&mut recv_buf)?;
-        if size == 0 {
-            continue;
+struct EchoServerCommand {
+    bind_addr: std::net::SocketAddr,
+    bind_device: Option<String>,
+    send_buf_size: usize,
+    one_per_packet: bool,
+    framing: crate::framing::Framing,
+    handler: EchoHandler,
+    silent_invalid: bool,
+    run_for: Option<std::time::Duration>,
+    limit_bps: Option<std::num::NonZeroU64>,
+    delay: Option<std::time::Duration>,
+    delay_rate: f64,
+    first_delay: Option<std::time::Duration>,
+    seed: Option<u64>,
+    per_peer: bool,
+    max_peers: Option<usize>,
+    peer_window: std::time::Duration,
+    max_bytes: Option<usize>,
+    exit_on_max_bytes: bool,
+    record: Option<std::path::PathBuf>,
+    verbosity: crate::Verbosity,
+}
+
+// Mutable state of a single `EchoServerCommand::run()` invocation
+#[derive(Debug)]
+struct EchoSession {
+    socket: std::net::UdpSocket,
+    limiter: Option<crate::utils::RateLimiter>,
+    recorder: Option<PacketRecorder>,
+    rng: crate::utils::Rng,
+    // `--first-delay-ms` (cleared once applied)
+    first_delay: Option<std::time::Duration>,
+    // `--max-bytes` (shared with the per-peer sessions)
+    budget: Option<std::sync::Arc<ByteBudget>>,
+}
+
+impl EchoSession {
+    fn send_to(&mut self, data: &[u8], addr: std::net::SocketAddr) -> std::io::Result<usize> {
+        if let Some(budget) = &self.budget
+            && !budget.consume(data.len())
+        {
+            return Ok(data.len()); // Silently dropped
+        }
+        if let Some(limiter) = &mut self.limiter {
+            limiter.acquire(data.len());
         }
+        self.socket.send_to(data, addr)
+    }
+}
 
-        let Ok(text) = std::str::from_utf8(&recv_buf[..size])
-            .inspect_err(|e| reply_err(&socket, peer_addr, -32700, e))
-        else {
-            continue;
-        };
+impl EchoServerCommand {
+    fn run(&self) -> crate::Result<()> {
+        let session = self.bind()?;
+        self.serve(session)
+    }
 
-        let mut send_buf_offset = 0;
-        for line in text.lines() {
-            let Ok(json) = nojson::RawJson::parse(line)
-                .inspect_err(|e| reply_err(&socket, peer_addr, -32700, e))
-            else {
-                continue;
+    fn bind(&self) -> crate::Result<EchoSession> {
+        let socket = std::net::UdpSocket::bind(self.bind_addr).map_err(|e| {
+            crate::Error::new(format!(
+                "failed to bind UDP socket to {}: {e}",
+                self.bind_addr
+            ))
+        })?;
+        if let Some(device) = &self.bind_device {
+            crate::utils::bind_to_device(&socket, device)?;
+        }
+        self.verbosity
+            .info(format_args!("listening on {}", socket.local_addr()?));
+        Ok(EchoSession {
+            socket,
+            limiter: self.limit_bps.map(crate::utils::RateLimiter::new),
+            recorder: self
+                .record
+                .as_deref()
+                .map(PacketRecorder::open)
+                .transpose()?,
+            rng: self
+                .seed
+                .map_or_else(crate::utils::Rng::from_time, crate::utils::Rng::new),
+            first_delay: self.first_delay,
+            budget: self.max_bytes.map(ByteBudget::new).map(std::sync::Arc::new),
+        })
+    }
+
+    fn serve(&self, mut session: EchoSession) -> crate::Result<()> {
+        if self.per_peer {
+            return std::thread::scope(|scope| self.run_per_peer(scope, session));
+        }
+
+        let mut recv_buf = vec![0u8; MAX_UDP_PACKET];
+        let mut send_buf = Vec::with_capacity(self.send_buf_size);
+        let deadline = self.run_for.map(|d| std::time::Instant::now() + d);
+        while let Some((size, peer_addr)) =
+            self.recv_packet(&mut session, &mut recv_buf, deadline)?
+        {
+            self.handle_packet(&mut session, peer_addr, &recv_buf[..size], &mut send_buf)?;
+            if self.is_budget_exhausted(&session) {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    // Routes packets to a dedicated thread per peer address; each thread owns its session
+    // (and thus its rate limiter), and threads are only terminated when the server exits
+    // or, with `--max-peers`, when the peer has been silent for `--peer-window`
+    fn run_per_peer<'scope>(
+        &'scope self,
+        scope: &'scope std::thread::Scope<'scope, '_>,
+        mut session: EchoSession,
+    ) -> crate::Result<()> {
+        let mut peers = std::collections::HashMap::new();
+        let mut recv_buf = vec![0u8; MAX_UDP_PACKET];
+        let deadline = self.run_for.map(|d| std::time::Instant::now() + d);
+        while let Some((size, peer_addr)) =
+            self.recv_packet(&mut session, &mut recv_buf, deadline)?
+        {
+            let now = std::time::Instant::now();
+            if let Some(max_peers) = self.max_peers {
+                // Dropping the sender makes the responder thread exit
+                peers.retain(|addr, (_, last_seen)| {
+                    let active = now.duration_since(*last_seen) < self.peer_window;
+                    if !active {
+                        self.verbosity.info(format_args!("forgot idle peer {addr}"));
+                    }
+                    active
+                });
+                if peers.len() >= max_peers && !peers.contains_key(&peer_addr) {
+                    self.verbosity.warn(format_args!(
+                        "rejected packet from {peer_addr}: already serving {max_peers} peers"
+                    ));
+                    let response = error_response(None, -32000, "too many peers");
+                    let mut packet = Vec::new();
+                    self.framing.encode(&mut packet, response.as_bytes());
+                    // Not subject to `--max-bytes` and `--limit-bps`, which are for served peers
+                    let _ = session.socket.send_to(&packet, peer_addr);
+                    continue;
+                }
+            }
+            let (tx, last_seen) = match peers.entry(peer_addr) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) => {
+                    let (tx, rx) = std::sync::mpsc::channel::<Vec<u8>>();
+                    let mut peer_session = EchoSession {
+                        socket: session.socket.try_clone()?,
+                        limiter: self.limit_bps.map(crate::utils::RateLimiter::new),
+                        recorder: None,
+                        rng: crate::utils::Rng::new(session.rng.next_u64()),
+                        // The first peer is the one that receives the first response
+                        first_delay: session.first_delay.take(),
+                        budget: session.budget.clone(),
+                    };
+                    self.verbosity
+                        .info(format_args!("started responder for {peer_addr}"));
+                    scope.spawn(move || {
+                        let mut send_buf = Vec::with_capacity(self.send_buf_size);
+                        for packet in rx {
+                            if let Err(e) = self.handle_packet(
+                                &mut peer_session,
+                                peer_addr,
+                                &packet,
+                                &mut send_buf,
+                            ) {
+                                self.verbosity
+                                    .warn(format_args!("responder for {peer_addr} failed: {e}"));
+                                break;
+                            }
+                            if self.is_budget_exhausted(&peer_session) {
+                                // The receiving thread is blocked on the socket, so exit from here
+                                std::process::exit(0);
+                            }
+                        }
+                    });
+                    entry.insert((tx, now))
+                }
             };
+            *last_seen = now;
+            let _ = tx.send(recv_buf[..size].to_vec()); // Ignores failed responders
+        }
+        Ok(())
+    }
 
-            let Ok(Some(id)) = crate::utils::validate_json_rpc_request(json.value())
-                .inspect_err(|e| reply_err(&socket, peer_addr, -32600, e))
-            else {
-                continue;
+    // Whether the server should exit because of `--exit-on-max-bytes`
+    fn is_budget_exhausted(&self, session: &EchoSession) -> bool {
+        let exhausted = self.exit_on_max_bytes
+            && session
+                .budget
+                .as_ref()
+                .is_some_and(|budget| budget.is_exhausted());
+        if exhausted {
+            self.verbosity.info("max-bytes budget exhausted; exiting");
+        }
+        exhausted
+    }
+
+    // Returns `Ok(None)` once the `--run-for` deadline has passed
+    fn recv_packet(
+        &self,
+        session: &mut EchoSession,
+        recv_buf: &mut [u8],
+        deadline: Option<std::time::Instant>,
+    ) -> crate::Result<Option<(usize, std::net::SocketAddr)>> {
+        loop {
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+                if remaining.is_zero() {
+                    self.verbosity.info("run-for duration elapsed; exiting");
+                    return Ok(None);
+                }
+                session.socket.set_read_timeout(Some(remaining))?;
+            }
+
+            let (size, peer_addr) = match session.socket.recv_from(recv_buf) {
+                Ok(received) => received,
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            self.verbosity.debug(format_args!(
+                "received packet: {size} bytes from {peer_addr}"
+            ));
+            if let Some(recorder) = &mut session.recorder {
+                let packet = &recv_buf[..size];
+                recorder.record(peer_addr, packet, self.is_valid_packet(packet))?;
+            }
+            if size > 0 {
+                return Ok(Some((size, peer_addr)));
+            }
+        }
+    }
+
+    // Whether every message in the packet is a well-formed JSON-RPC request
+    fn is_valid_packet(&self, mut packet: &[u8]) -> bool {
+        loop {
+            match self.framing.read_message(&mut packet) {
+                Ok(Some(message)) => {
+                    let Ok(json) = nojson::RawJson::parse(&message) else {
+                        return false;
+                    };
+                    if crate::utils::validate_json_rpc_request(json.value(), Default::default())
+                        .is_err()
+                    {
+                        return false;
+                    }
+                }
+                Ok(None) => return true,
+                Err(_) => return false,
+            }
+        }
+    }
+
+    fn handle_packet(
+        &self,
+        session: &mut EchoSession,
+        peer_addr: std::net::SocketAddr,
+        packet: &[u8],
+        send_buf: &mut Vec<u8>,
+    ) -> crate::Result<()> {
+        let mut reader = packet;
+        loop {
+            let message = match self.framing.read_message(&mut reader) {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(e) => {
+                    self.reply_invalid(session, peer_addr, &error_response(None, -32700, e));
+                    break;
+                }
+            };
+
+            let (id, response) = match self.handler.handle_request(&message) {
+                EchoOutcome::Result { id, response } => (id, response),
+                EchoOutcome::Error(response) => {
+                    self.reply_err(session, peer_addr, &response);
+                    continue;
+                }
+                EchoOutcome::Invalid(response) => {
+                    self.reply_invalid(session, peer_addr, &response);
+                    continue;
+                }
+                EchoOutcome::NoResponse => continue,
             };
 
-            let response = nojson::object(|f| {
-                f.member("jsonrpc", "2.0")?;
-                f.member("id", id)?;
-                f.member("result", &json)
-            })
-            .to_string();
             let response_bytes = response.as_bytes();
-            let size = response_bytes.len();
-            if size > send_buf.len() {
-                reply_err(
-                    &socket,
-                    peer_addr,
+            if self.framing.encoded_len(&[], response_bytes.len()) > self.send_buf_size {
+                let response = error_response(
+                    Some(id.value()),
                     -32603,
                     "response size exceeds maximum UDP packet size",
                 );
+                self.reply_err(session, peer_addr, &response);
                 continue;
             }
 
-            if send_buf_offset != 0 && send_buf_offset + 1 + size > send_buf.len() {
-                let sent = socket.send_to(&send_buf[..send_buf_offset], peer_addr)?;
-                if sent != send_buf_offset {
-                    return Err(crate::Error::new("failed to send complete response"));
+            if let Some(delay) = session.first_delay.take() {
+                self.verbosity
+                    .debug(format_args!("delaying the first response by {delay:?}"));
+                std::thread::sleep(delay);
+            }
+            if let Some(delay) = self.delay
+                && session.rng.next_f64() < self.delay_rate
+            {
+                // Sends the preceding responses first so that only this one is delayed
+                if !send_buf.is_empty() {
+                    self.send_packet(session, peer_addr, send_buf)?;
                 }
-                send_buf_offset = 0;
+                std::thread::sleep(delay);
             }
-
-            if send_buf_offset != 0 {
-                send_buf[send_buf_offset] = b'\n';
-                send_buf_offset += 1;
+            if !send_buf.is_empty()
+                && send_buf.len() + self.framing.encoded_len(send_buf, response_bytes.len())
+                    > self.send_buf_size
+            {
+                self.send_packet(session, peer_addr, send_buf)?;
             }
+            self.framing.encode(send_buf, response_bytes);
+            if self.one_per_packet {
+                self.send_packet(session, peer_addr, send_buf)?;
+            }
+        }
 
-            send_buf[send_buf_offset..][..size].copy_from_slice(response_bytes);
-            send_buf_offset += size;
+        if !send_buf.is_empty() {
+            self.send_packet(session, peer_addr, send_buf)?;
         }
+        Ok(())
+    }
 
-        if send_buf_offset != 0 {
-            let size = socket.send_to(&send_buf[..send_buf_offset], peer_addr)?;
-            if size != send_buf_offset {
-                return Err(crate::Error::new("failed to send complete response"));
-            }
+    fn send_packet(
+        &self,
+        session: &mut EchoSession,
+        peer_addr: std::net::SocketAddr,
+        send_buf: &mut Vec<u8>,
+    ) -> crate::Result<()> {
+        let size = session.send_to(send_buf, peer_addr)?;
+        if size != send_buf.len() {
+            return Err(crate::Error::new("failed to send complete response"));
         }
+        self.verbosity
+            .debug(format_args!("sent packet: {size} bytes to {peer_addr}"));
+        send_buf.clear();
+        Ok(())
+    }
+
+    // Replies to malformed input unless `--silent-invalid` is specified
+    fn reply_invalid(&self, session: &mut EchoSession, addr: std::net::SocketAddr, response: &str) {
+        if self.silent_invalid {
+            self.verbosity.debug(format_args!(
+                "dropped invalid input from {addr}: {response}"
+            ));
+            return;
+        }
+        self.reply_err(session, addr, response);
+    }
+
+    fn reply_err(&self, session: &mut EchoSession, addr: std::net::SocketAddr, response: &str) {
+        let mut packet = Vec::new();
+        self.framing.encode(&mut packet, response.as_bytes());
+        let _ = session.send_to(&packet, addr); // Ignores the result for simplicity
+    }
+}
+
+// `--max-bytes BYTES`
+#[derive(Debug)]
+struct ByteBudget {
+    max: usize,
+    sent: std::sync::atomic::AtomicUsize,
+    exhausted: std::sync::atomic::AtomicBool,
+}
+
+impl ByteBudget {
+    fn new(max: usize) -> Self {
+        Self {
+            max,
+            sent: std::sync::atomic::AtomicUsize::new(0),
+            exhausted: std::sync::atomic::AtomicBool::new(false),
+        }
+    }
+
+    // Returns `false` (and exhausts the budget for good) if `size` more bytes do not fit
+    fn consume(&self, size: usize) -> bool {
+        use std::sync::atomic::Ordering;
+
+        if self.exhausted.load(Ordering::SeqCst) {
+            return false;
+        }
+        let result = self
+            .sent
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |sent| {
+                sent.checked_add(size).filter(|&total| total <= self.max)
+            });
+        if result.is_err() {
+            self.exhausted.store(true, Ordering::SeqCst);
+        }
+        result.is_ok()
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.exhausted.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+// `--record PATH`
+#[derive(Debug)]
+struct PacketRecorder {
+    file: std::fs::File,
+}
+
+impl PacketRecorder {
+    fn open(path: &std::path::Path) -> crate::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| crate::Error::new(format!("failed to open {}: {e}", path.display())))?;
+        Ok(Self { file })
+    }
+
+    fn record(
+        &mut self,
+        peer_addr: std::net::SocketAddr,
+        packet: &[u8],
+        valid: bool,
+    ) -> crate::Result<()> {
+        use std::io::Write;
+
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let line = nojson::object(|f| {
+            f.member("peer", peer_addr.to_string())?;
+            f.member("timestamp", timestamp)?;
+            f.member("size", packet.len())?;
+            f.member("valid", valid)?;
+            f.member("data", String::from_utf8_lossy(packet).as_ref())
+        });
+        // A single unbuffered write per packet keeps the file complete if the server is killed
+        self.file.write_all(format!("{line}\n").as_bytes())?;
+        Ok(())
     }
 }
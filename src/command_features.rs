@@ -0,0 +1,62 @@
+pub fn try_run(args: &mut noargs::RawArgs) -> noargs::Result<bool> {
+    if !noargs::cmd("features")
+        .doc("Print the capabilities (commands, transports and framings) of this build as JSON")
+        .take(args)
+        .is_present()
+    {
+        return Ok(false);
+    }
+
+    if args.metadata().help_mode {
+        return Ok(true);
+    }
+
+    let features = nojson::object(|f| {
+        f.member("version", env!("CARGO_PKG_VERSION"))?;
+        f.member(
+            "cargo_features",
+            nojson::array(|f| {
+                if cfg!(feature = "msgpack") {
+                    f.element("msgpack")?;
+                }
+                Ok(())
+            }),
+        )?;
+        f.member(
+            "commands",
+            [
+                "req",
+                "call",
+                "echo-server",
+                "check",
+                "probe-mtu",
+                "order-check",
+                "features",
+            ],
+        )?;
+        f.member("transports", ["udp", "exec"])?;
+        f.member("framings", ["lines", "content-length"])?;
+        f.member(
+            "input_formats",
+            nojson::array(|f| {
+                f.elements(["auto", "ndjson", "json"])?;
+                if cfg!(feature = "msgpack") {
+                    f.element("msgpack")?;
+                }
+                Ok(())
+            }),
+        )?;
+        f.member(
+            "output_formats",
+            nojson::array(|f| {
+                f.element("json")?;
+                if cfg!(feature = "msgpack") {
+                    f.element("msgpack")?;
+                }
+                Ok(())
+            }),
+        )
+    });
+    crate::utils::write_line(&mut std::io::stdout().lock(), features)?;
+    Ok(true)
+}
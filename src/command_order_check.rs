@@ -0,0 +1,145 @@
+use std::collections::BTreeSet;
+
+pub fn try_run(args: &mut noargs::RawArgs, verbosity: crate::Verbosity) -> noargs::Result<bool> {
+    if !noargs::cmd("order-check")
+        .doc(concat!(
+            "Check responses read from standard input for missing, duplicate and reordered ids\n",
+            "\n",
+            "This command assumes the requests had sequential integer ids (e.g., generated\n",
+            "by `jlou req --count N`) and prints a JSON summary of the problems found."
+        ))
+        .take(args)
+        .is_present()
+    {
+        return Ok(false);
+    }
+
+    let count: Option<usize> = noargs::opt("count")
+        .short('c')
+        .ty("INTEGER")
+        .doc("Number of requests that were sent (default: up to the largest id received)")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let first_id: u64 = noargs::opt("first-id")
+        .ty("INTEGER")
+        .doc("Id of the first request (e.g., when generated with `req --id-state`)")
+        .default("0")
+        .take(args)
+        .then(|o| o.value().parse())?;
+    let input_format: crate::input::InputFormat = noargs::opt("format")
+        .ty("auto | ndjson | json")
+        .doc("Format of the responses read from stdin")
+        .default("auto")
+        .take(args)
+        .then(|o| o.value().parse())?;
+
+    if args.metadata().help_mode {
+        return Ok(true);
+    }
+
+    let command = OrderCheckCommand {
+        count,
+        first_id,
+        input_format,
+        verbosity,
+    };
+    command.run()?;
+    Ok(true)
+}
+
+struct OrderCheckCommand {
+    count: Option<usize>,
+    first_id: u64,
+    input_format: crate::input::InputFormat,
+    verbosity: crate::Verbosity,
+}
+
+impl OrderCheckCommand {
+    fn run(&self) -> crate::Result<()> {
+        // Exclusive upper bound of the expected ids
+        let end = self
+            .count
+            .map(|count| self.first_id.saturating_add(count as u64));
+        let mut report = OrderReport::default();
+        let mut seen = BTreeSet::new();
+        let mut max_seen = None;
+        let stdin = std::io::stdin().lock();
+        for line in crate::input::RequestReader::new(stdin, self.input_format) {
+            let line = line?;
+            let json = nojson::RawJson::parse(&line)?;
+            report.responses += 1;
+
+            let id = json.value().to_member("id")?.get();
+            let Some(id) = id
+                .filter(|id| id.kind() == nojson::JsonValueKind::Integer)
+                .and_then(|id| id.as_raw_str().parse::<u64>().ok())
+                .filter(|&id| id >= self.first_id && end.is_none_or(|end| id < end))
+            else {
+                let id = id.map_or("null", |id| id.as_raw_str());
+                self.verbosity
+                    .info(format_args!("unexpected response id {id}"));
+                report.unexpected.push(id.to_owned());
+                continue;
+            };
+            if !seen.insert(id) {
+                self.verbosity
+                    .info(format_args!("duplicate response id {id}"));
+                report.duplicates.insert(id);
+                continue;
+            }
+            if max_seen.is_some_and(|max| id < max) {
+                self.verbosity
+                    .info(format_args!("response id {id} arrived out of order"));
+                report.out_of_order.push(id);
+            }
+            max_seen = max_seen.max(Some(id));
+        }
+
+        let end = end.unwrap_or(max_seen.map_or(self.first_id, |id| id + 1));
+        report.expected = end - self.first_id;
+        report.missing = (self.first_id..end)
+            .filter(|id| !seen.contains(id))
+            .collect();
+
+        crate::utils::write_line(&mut std::io::stdout().lock(), nojson::Json(&report))?;
+        if !report.is_ok() {
+            return Err(crate::Error::new(
+                "responses are incomplete, duplicated or out of order",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct OrderReport {
+    responses: usize,
+    expected: u64,
+    missing: Vec<u64>,
+    duplicates: BTreeSet<u64>,
+    out_of_order: Vec<u64>,
+    // Raw JSON ids that are not integers in the expected range
+    unexpected: Vec<String>,
+}
+
+impl OrderReport {
+    fn is_ok(&self) -> bool {
+        self.missing.is_empty()
+            && self.duplicates.is_empty()
+            && self.out_of_order.is_empty()
+            && self.unexpected.is_empty()
+    }
+}
+
+impl nojson::DisplayJson for OrderReport {
+    fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
+        f.object(|f| {
+            f.member("responses", self.responses)?;
+            f.member("expected", self.expected)?;
+            f.member("missing", &self.missing)?;
+            f.member("duplicates", &self.duplicates)?;
+            f.member("out_of_order", &self.out_of_order)?;
+            f.member("unexpected", &self.unexpected)
+        })
+    }
+}
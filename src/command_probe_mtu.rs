@@ -0,0 +1,232 @@
+use std::time::Duration;
+
+const MAX_UDP_PACKET: usize = 65507;
+
+// Ids of the probes count up from here, so that they all have the same number of digits
+// (and every probe of a given size is exactly that size)
+const FIRST_PROBE_ID: usize = 1_000_000_000;
+
+pub fn try_run(args: &mut noargs::RawArgs, verbosity: crate::Verbosity) -> noargs::Result<bool> {
+    if !noargs::cmd("probe-mtu")
+        .doc(concat!(
+            "Find the largest UDP packet that round-trips through an echo server\n",
+            "\n",
+            "This command binary-searches the size of request packets (sent with the\n",
+            "don't-fragment bit where supported) and prints the largest size answered\n",
+            "with a result, along with the corresponding MTU, as JSON."
+        ))
+        .take(args)
+        .is_present()
+    {
+        return Ok(false);
+    }
+
+    let max_size: usize = noargs::opt("max")
+        .ty("BYTES")
+        .doc("Largest UDP payload size to try")
+        .default("65507")
+        .take(args)
+        .then(|o| o.value().parse())?;
+    let timeout: Duration = noargs::opt("timeout")
+        .ty("SECONDS")
+        .doc("Time to wait for the response to each probe")
+        .default("0.5")
+        .take(args)
+        .then(|o| crate::utils::parse_duration_secs(o.value()))?;
+    let retries: usize = noargs::opt("retries")
+        .ty("COUNT")
+        .doc("Number of extra attempts before a probe size is considered to fail")
+        .default("2")
+        .take(args)
+        .then(|o| o.value().parse())?;
+    let server_addr: std::net::SocketAddr = noargs::arg("<SERVER>")
+        .doc("Echo server address or hostname (e.g., started by `jlou echo-server -b 65507`)")
+        .example("127.0.0.1:9000")
+        .take(args)
+        .then(|a| crate::utils::parse_socket_addr(a.value()))?;
+
+    if args.metadata().help_mode {
+        return Ok(true);
+    }
+    if max_size > MAX_UDP_PACKET {
+        return Err(noargs::Error::other(
+            args,
+            format!("--max must be <= {MAX_UDP_PACKET}"),
+        ));
+    }
+    if timeout.is_zero() {
+        return Err(noargs::Error::other(
+            args,
+            "--timeout must be greater than 0",
+        ));
+    }
+
+    let command = ProbeMtuCommand {
+        server_addr,
+        max_size,
+        timeout,
+        retries,
+        verbosity,
+    };
+    command.run()?;
+    Ok(true)
+}
+
+struct ProbeMtuCommand {
+    server_addr: std::net::SocketAddr,
+    max_size: usize,
+    timeout: Duration,
+    retries: usize,
+    verbosity: crate::Verbosity,
+}
+
+impl ProbeMtuCommand {
+    fn run(&self) -> crate::Result<()> {
+        let socket = crate::command_call::connect_to_server_udp(self.server_addr, None, None)?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        if let Err(e) = crate::utils::set_dont_fragment(&socket) {
+            self.verbosity
+                .warn(format_args!("{}; packets may be fragmented", e.reason));
+        }
+
+        // The smallest probe is a request with an empty padding string
+        let min_size = probe_request(FIRST_PROBE_ID, 0).len();
+        let mut next_id = FIRST_PROBE_ID;
+        if min_size > self.max_size || !self.probe(&socket, &mut next_id, min_size)? {
+            return Err(crate::Error::new(format!(
+                "no result for a probe of {min_size} bytes from {}",
+                self.server_addr
+            )));
+        }
+
+        // Invariant: `low` round-trips and sizes above `high` do not
+        let (mut low, mut high) = (min_size, self.max_size);
+        while low < high {
+            let size = low + (high - low).div_ceil(2);
+            if self.probe(&socket, &mut next_id, size)? {
+                low = size;
+            } else {
+                high = size - 1;
+            }
+        }
+
+        let header_size = if self.server_addr.is_ipv6() {
+            crate::command_call::IPV6_HEADER_SIZE + crate::command_call::UDP_HEADER_SIZE
+        } else {
+            crate::command_call::IPV4_HEADER_SIZE + crate::command_call::UDP_HEADER_SIZE
+        };
+        let report = nojson::object(|f| {
+            f.member("server", self.server_addr.to_string())?;
+            f.member("max_payload_size", low)?;
+            f.member("mtu", low + header_size)
+        });
+        crate::utils::write_line(&mut std::io::stdout().lock(), report)?;
+        Ok(())
+    }
+
+    // Returns whether a request packet of `size` bytes is answered with a result
+    fn probe(
+        &self,
+        socket: &std::net::UdpSocket,
+        next_id: &mut usize,
+        size: usize,
+    ) -> crate::Result<bool> {
+        for attempt in 0..=self.retries {
+            let id = *next_id;
+            *next_id += 1;
+            // Only reachable after billions of probes, when the id gains a digit
+            let Some(padding) = size.checked_sub(probe_request(id, 0).len()) else {
+                return Ok(false);
+            };
+            let request = probe_request(id, padding);
+            if let Err(e) = socket.send(request.as_bytes()) {
+                // e.g. EMSGSIZE once the don't-fragment bit is set and the size exceeds the MTU
+                self.verbosity
+                    .info(format_args!("probe of {size} bytes: failed to send: {e}"));
+                return Ok(false);
+            }
+            match self.recv_result(socket, id)? {
+                Some(true) => {
+                    self.verbosity
+                        .info(format_args!("probe of {size} bytes: ok"));
+                    return Ok(true);
+                }
+                Some(false) => {
+                    // The server is reachable but could not echo the request back
+                    self.verbosity
+                        .info(format_args!("probe of {size} bytes: error response"));
+                    return Ok(false);
+                }
+                None => self.verbosity.info(format_args!(
+                    "probe of {size} bytes: no response (attempt {} of {})",
+                    attempt + 1,
+                    self.retries + 1
+                )),
+            }
+        }
+        Ok(false)
+    }
+
+    // Returns whether the response with `id` has a result (`None` on timeout)
+    fn recv_result(&self, socket: &std::net::UdpSocket, id: usize) -> crate::Result<Option<bool>> {
+        let mut recv_buf = vec![0u8; MAX_UDP_PACKET];
+        loop {
+            let size = match socket.recv(&mut recv_buf) {
+                Ok(size) => size,
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    return Ok(None);
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let Ok(text) = std::str::from_utf8(&recv_buf[..size]) else {
+                continue;
+            };
+            let Ok(json) = nojson::RawJson::parse(text.trim_end()) else {
+                continue;
+            };
+            let response_id: Option<usize> = json
+                .value()
+                .to_member("id")
+                .ok()
+                .and_then(|m| m.get())
+                .and_then(|id| id.try_into().ok());
+            if response_id != Some(id) {
+                continue; // Late response to an earlier attempt
+            }
+            let has_result = json
+                .value()
+                .to_member("result")
+                .is_ok_and(|m| m.get().is_some());
+            return Ok(Some(has_result));
+        }
+    }
+}
+
+// Request whose params hold a padding string of `padding` bytes
+fn probe_request(id: usize, padding: usize) -> String {
+    let params =
+        nojson::RawJsonOwned::parse(nojson::json(|f| f.value(["x".repeat(padding)])).to_string())
+            .expect("valid JSON");
+    crate::command_req::request_json("probe", Some(&params), Some(id)).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes_of_a_size_have_the_same_length_regardless_of_id() {
+        let min_size = probe_request(FIRST_PROBE_ID, 0).len();
+        for id in [
+            FIRST_PROBE_ID + 9,
+            FIRST_PROBE_ID + 10,
+            FIRST_PROBE_ID + 123_456,
+        ] {
+            assert_eq!(probe_request(id, 0).len(), min_size);
+            assert_eq!(probe_request(id, 100).len(), min_size + 100);
+        }
+    }
+}
@@ -1,6 +1,4 @@
-use std::num::NonZeroUsize;
-
-pub fn try_run(args: &mut noargs::RawArgs) -> noargs::Result<bool> {
+pub fn try_run(args: &mut noargs::RawArgs, verbosity: crate::Verbosity) -> noargs::Result<bool> {
     if !noargs::cmd("req")
         .doc("Generate JSON-RPC request object JSON")
         .take(args)
@@ -14,53 +12,733 @@ pub fn try_run(args: &mut noargs::RawArgs) -> noargs::Result<bool> {
         .doc("Exclude the \"id\" field from the resulting JSON object")
         .take(args)
         .is_present();
-    let count: NonZeroUsize = noargs::opt("count")
+    let notification_ratio: f64 = noargs::opt("notification-ratio")
+        .ty("RATIO")
+        .doc("Fraction (0.0 to 1.0) of requests randomly generated as notifications")
+        .default("0")
+        .take(args)
+        .then(|o| {
+            let ratio: f64 = o.value().parse().map_err(|e| format!("{e}"))?;
+            if !(0.0..=1.0).contains(&ratio) {
+                return Err("must be between 0.0 and 1.0".to_owned());
+            }
+            Ok(ratio)
+        })?;
+    let version: JsonRpcVersion = noargs::opt("jsonrpc")
+        .ty("2.0 | 1.0")
+        .doc(concat!(
+            "Protocol version of the generated requests\n",
+            "(`1.0` omits the \"jsonrpc\" member, always includes \"params\" and uses a null id for notifications)"
+        ))
+        .default("2.0")
+        .take(args)
+        .then(|o| o.value().parse())?;
+    let seed: Option<u64> = noargs::opt("seed")
+        .ty("INTEGER")
+        .doc("Seed of the random number generator (default: derived from the current time)")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let count: Option<usize> = noargs::opt("count")
         .short('c')
         .ty("INTEGER")
-        .doc("Count of requests to generate")
-        .default("1")
+        .doc("Count of requests to generate (0 means infinite; default: 1, or the number of --params-array / --from-file entries)")
         .take(args)
-        .then(|o| o.value().parse())?;
-    let params: Option<nojson::RawJsonOwned> = noargs::opt("params")
+        .present_and_then(|o| o.value().parse())?;
+    let json_errors: bool = noargs::flag("json-errors")
+        .doc("Report JSON parse errors of --params / --params-array / --preset as a JSON object (with its location)")
+        .take(args)
+        .is_present();
+    let expand_env: bool = noargs::flag("expand-env")
+        .doc("Expand `${VAR}` in --params / --params-array with environment variables before parsing")
+        .take(args)
+        .is_present();
+    let expand_env_default: Option<String> = noargs::opt("expand-env-default")
+        .ty("VALUE")
+        .doc("Value substituted for unset variables with --expand-env (default: unset variables are an error)")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let relaxed_params: bool = noargs::flag("relaxed-params")
+        .doc("Accept unquoted object keys and single-quoted strings in --params / --params-array")
+        .take(args)
+        .is_present();
+    let load_params = |text: &str| -> crate::Result<String> {
+        let mut text = read_params_text(text)?;
+        if expand_env {
+            text = expand_env_vars(&text, expand_env_default.as_deref())?;
+        }
+        if relaxed_params {
+            text = normalize_relaxed_json(&text);
+        }
+        Ok(text)
+    };
+    // The JSON texts are parsed after all options are taken (see `parse_error()`)
+    let params: Option<String> = noargs::opt("params")
         .short('p')
         .ty("OBJECT | ARRAY")
-        .doc("Request parameters (JSON array or JSON object)")
-        .take(args)
-        .present_and_then(|a| {
-            let json = nojson::RawJson::parse(a.value())?;
-            if !matches!(
-                json.value().kind(),
-                nojson::JsonValueKind::Array | nojson::JsonValueKind::Object
-            ) {
-                return Err(json.value().invalid("must be a JSON array or JSON object"));
+        .doc("Request parameters (JSON array or JSON object, or `@PATH` to read them from a file)")
+        .take(args)
+        .present_and_then(|a| load_params(a.value()))?;
+    let params_array: Option<String> = noargs::opt("params-array")
+        .ty("ARRAY")
+        .doc(concat!(
+            "JSON array whose i-th element is used as the params of the i-th request\n",
+            "(elements must be arrays or objects; `@PATH` reads the array from a file)"
+        ))
+        .example("[[1],[2],[3]]")
+        .take(args)
+        .present_and_then(|a| load_params(a.value()))?;
+    let presets: Vec<(String, String)> = crate::utils::take_repeated_opt(
+        noargs::opt("preset")
+            .ty("NAME=PARAMS")
+            .doc("Define named request parameters selectable with --use-preset (repeatable)")
+            .example("small={\"n\":1}"),
+        args,
+        |o| -> crate::Result<_> {
+            let (name, text) = o
+                .value()
+                .split_once('=')
+                .ok_or_else(|| crate::Error::new("expected NAME=PARAMS"))?;
+            Ok((name.to_owned(), load_params(text)?))
+        },
+    )?;
+    let use_preset: Option<String> = noargs::opt("use-preset")
+        .ty("NAME")
+        .doc("Use the --preset named NAME as the request parameters")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let cycle: bool = noargs::flag("cycle")
+        .doc("Reuse --params-array / --from-file entries from the beginning if --count exceeds their number")
+        .take(args)
+        .is_present();
+    let id_state: Option<std::path::PathBuf> = noargs::opt("id-state")
+        .ty("PATH")
+        .doc(concat!(
+            "Continue ids after the last one recorded in PATH and record the new last id\n",
+            "(keeps ids unique across multiple invocations; requires a non-zero --count)"
+        ))
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let output: Option<std::path::PathBuf> = noargs::opt("output")
+        .short('o')
+        .ty("PATH")
+        .doc("Write the generated requests to PATH instead of stdout ('-' for stdout)")
+        .take(args)
+        .present_and_then(|o| o.value().parse())?;
+    let append: bool = noargs::flag("append")
+        .doc("Append to the --output file instead of truncating it")
+        .take(args)
+        .is_present();
+    let sort_keys: bool = noargs::flag("sort-keys")
+        .doc("Sort the members of the generated JSON objects by name")
+        .take(args)
+        .is_present();
+    let allow_empty_method: bool = noargs::flag("allow-empty-method")
+        .doc("Accept an empty or whitespace-only method name")
+        .take(args)
+        .is_present();
+    let from_file: Option<Vec<TableEntry>> = noargs::opt("from-file")
+        .ty("PATH")
+        .doc(concat!(
+            "Generate one request per line of PATH in the form `METHOD<TAB>PARAMS` instead of <METHOD>\n",
+            "(PARAMS is a JSON array or object, and may be omitted)"
+        ))
+        .take(args)
+        .present_and_then(|o| load_table(o.value(), allow_empty_method))?;
+    let method_template: Option<String> = noargs::opt("method-template")
+        .ty("TEMPLATE")
+        .doc(concat!(
+            "Generate method names from TEMPLATE instead of <METHOD>\n",
+            "(`%i` is replaced by the request counter, which also advances for notifications)"
+        ))
+        .example("get_item_%i")
+        .take(args)
+        .present_and_then(|o| {
+            if !allow_empty_method && o.value().trim().is_empty() {
+                return Err("method template must not be empty");
             }
-            Ok(json.into_owned())
+            Ok(o.value().to_owned())
         })?;
-    let method: String = noargs::arg("<METHOD>")
-        .doc("Method name")
-        .example("GetFoo")
+    let method_case: crate::utils::MethodCase = noargs::opt("method-case")
+        .ty("asis | lower | upper")
+        .doc("Convert the generated method names to lowercase or uppercase")
+        .default("asis")
         .take(args)
-        .then(|a| a.value().parse())?;
+        .then(|o| o.value().parse())?;
+    let method: String = if let Some(template) = &method_template {
+        template.clone()
+    } else if from_file.is_some() {
+        String::new() // Taken from each entry
+    } else {
+        noargs::arg("<METHOD>")
+            .doc("Method name")
+            .example("GetFoo")
+            .take(args)
+            .then(|a| {
+                if !allow_empty_method && a.value().trim().is_empty() {
+                    return Err("method name must not be empty");
+                }
+                Ok(a.value().to_owned())
+            })?
+    };
 
     if args.metadata().help_mode {
         return Ok(true);
     }
 
+    if expand_env_default.is_some() && !expand_env {
+        return Err(noargs::Error::other(
+            args,
+            "--expand-env-default requires --expand-env",
+        ));
+    }
+    let params = params
+        .map(|text| {
+            parse_params_json(&text).map_err(|e| parse_error("--params", &text, e, json_errors))
+        })
+        .transpose()?;
+    let params_array = params_array
+        .map(|text| {
+            parse_params_array(&text)
+                .map_err(|e| parse_error("--params-array", &text, e, json_errors))
+        })
+        .transpose()?;
+    let presets = presets
+        .into_iter()
+        .map(|(name, text)| {
+            parse_params_json(&text)
+                .map(|params| (name, params))
+                .map_err(|e| parse_error("--preset", &text, e, json_errors))
+        })
+        .collect::<crate::Result<Vec<_>>>()?;
+    if from_file.is_some()
+        && (method_template.is_some() || params.is_some() || params_array.is_some())
+    {
+        return Err(noargs::Error::other(
+            args,
+            "--from-file cannot be combined with --method-template, --params or --params-array",
+        ));
+    }
+    for (i, (name, _)) in presets.iter().enumerate() {
+        if presets[..i].iter().any(|(n, _)| n == name) {
+            return Err(noargs::Error::other(
+                args,
+                format!("--preset {name:?} is defined more than once"),
+            ));
+        }
+    }
+    let params = match &use_preset {
+        Some(_) if params.is_some() || params_array.is_some() || from_file.is_some() => {
+            return Err(noargs::Error::other(
+                args,
+                "--use-preset cannot be combined with --params, --params-array or --from-file",
+            ));
+        }
+        Some(name) => {
+            let Some((_, preset)) = presets.into_iter().find(|(n, _)| n == name) else {
+                return Err(noargs::Error::other(
+                    args,
+                    format!("unknown preset {name:?} (define it with --preset {name}=PARAMS)"),
+                ));
+            };
+            Some(preset)
+        }
+        None => params,
+    };
+    let count = count.unwrap_or(match (&from_file, &params_array) {
+        (Some(entries), _) => entries.len(),
+        (None, Some(params_array)) => params_array.len(),
+        (None, None) => 1,
+    });
+    if let Some(entries) = &from_file
+        && !cycle
+        && (count == 0 || count > entries.len())
+    {
+        return Err(noargs::Error::other(
+            args,
+            format!(
+                "--count exceeds the number of --from-file entries ({}); specify --cycle to reuse them",
+                entries.len()
+            ),
+        ));
+    }
+    if let Some(params_array) = &params_array {
+        if params.is_some() {
+            return Err(noargs::Error::other(
+                args,
+                "--params and --params-array cannot be specified together",
+            ));
+        }
+        if !cycle && (count == 0 || count > params_array.len()) {
+            return Err(noargs::Error::other(
+                args,
+                format!(
+                    "--count exceeds the length of --params-array ({}); specify --cycle to reuse the elements",
+                    params_array.len()
+                ),
+            ));
+        }
+    }
+    if append && output.as_ref().is_none_or(|path| path.as_os_str() == "-") {
+        return Err(noargs::Error::other(
+            args,
+            "--append requires --output PATH",
+        ));
+    }
+    if id_state.is_some() && count == 0 {
+        return Err(noargs::Error::other(
+            args,
+            "--id-state cannot be used with an infinite --count",
+        ));
+    }
+
+    let methods = match &from_file {
+        Some(entries) => entries.iter().map(|entry| entry.method.as_str()).collect(),
+        None => vec![method.as_str()],
+    };
+    for method in methods.into_iter().filter(|m| m.starts_with("rpc.")) {
+        verbosity.warn(format_args!(
+            "method names beginning with \"rpc.\" are reserved by the JSON-RPC 2.0 specification: {method:?}"
+        ));
+    }
+
+    let first_id = match &id_state {
+        Some(path) if !notification => reserve_ids(path, count)?,
+        _ => 0,
+    };
+
     // Generate and output requests
-    for id in 0..count.get() {
-        let json = nojson::object(|f| {
-            f.member("jsonrpc", "2.0")?;
-            f.member("method", &method)?;
-            if let Some(params) = &params {
-                f.member("params", params)?;
-            }
-            if !notification {
-                f.member("id", id)?;
+    let mut rng = seed.map_or_else(crate::utils::Rng::from_time, crate::utils::Rng::new);
+    let start_time = std::time::Instant::now();
+    let mut writer: Box<dyn std::io::Write> = match &output {
+        Some(path) if path.as_os_str() != "-" => {
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(append)
+                .truncate(!append)
+                .open(path)
+                .map_err(|e| {
+                    crate::Error::new(format!("failed to open {}: {e}", path.display()))
+                })?;
+            Box::new(std::io::BufWriter::new(file))
+        }
+        _ => Box::new(std::io::stdout().lock()),
+    };
+    let mut generated = 0usize;
+    for i in (0..).take_while(|&i| count == 0 || i < count) {
+        let id = first_id + i;
+        let is_notification =
+            notification || (notification_ratio > 0.0 && rng.next_f64() < notification_ratio);
+        let (method, params) = match (&from_file, &params_array) {
+            (Some(entries), _) => {
+                let entry = &entries[i % entries.len()];
+                (&entry.method, entry.params.as_ref())
             }
-            Ok(())
-        });
-        println!("{json}");
+            (None, Some(params_array)) => (&method, Some(&params_array[i % params_array.len()])),
+            (None, None) => (&method, params.as_ref()),
+        };
+        // The counter advances even for notifications, which have no id member
+        let templated;
+        let method = if method_template.is_some() {
+            templated = method.replace("%i", &id.to_string());
+            &templated
+        } else {
+            method
+        };
+        let normalized;
+        let method = if method_case != crate::utils::MethodCase::AsIs {
+            normalized = method_case.apply(method);
+            &normalized
+        } else {
+            method
+        };
+        let id = (!is_notification).then_some(id);
+        let json = match version {
+            JsonRpcVersion::V2 => request_json(method, params, id).to_string(),
+            JsonRpcVersion::V1 => request_json_v1(method, params, id).to_string(),
+        };
+        let written = if sort_keys {
+            let json = nojson::RawJson::parse(&json)?.into_owned();
+            crate::utils::write_line(
+                &mut writer,
+                nojson::json(|f| f.value(crate::utils::SortedKeys(json.value()))),
+            )?
+        } else {
+            crate::utils::write_line(&mut writer, json)?
+        };
+        if !written {
+            break;
+        }
+        generated += 1;
+    }
+    if let Err(e) = writer.flush()
+        && e.kind() != std::io::ErrorKind::BrokenPipe
+    {
+        return Err(e.into());
     }
+    verbosity.info(format_args!(
+        "generated {generated} requests in {:?}",
+        start_time.elapsed()
+    ));
 
     Ok(true)
 }
+
+// Line of a `--from-file` table
+#[derive(Debug)]
+struct TableEntry {
+    method: String,
+    params: Option<nojson::RawJsonOwned>,
+}
+
+fn load_table(path: &str, allow_empty_method: bool) -> crate::Result<Vec<TableEntry>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| crate::Error::new(format!("failed to read {path}: {e}")))?;
+    let mut entries = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (method, params) = line.split_once('\t').unwrap_or((line, ""));
+        if !allow_empty_method && method.trim().is_empty() {
+            return Err(crate::Error::new(format!(
+                "{path}:{}: method name must not be empty",
+                i + 1
+            )));
+        }
+        let params = if params.trim().is_empty() {
+            None
+        } else {
+            let params = parse_params_json(params)
+                .map_err(|e| crate::Error::new(format!("{path}:{}: {e}", i + 1)))?;
+            Some(params)
+        };
+        entries.push(TableEntry {
+            method: method.to_owned(),
+            params,
+        });
+    }
+    if entries.is_empty() {
+        return Err(crate::Error::new(format!("{path} has no entries")));
+    }
+    Ok(entries)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum JsonRpcVersion {
+    #[default]
+    V2,
+    V1,
+}
+
+impl std::str::FromStr for JsonRpcVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "2.0" => Ok(Self::V2),
+            "1.0" => Ok(Self::V1),
+            _ => Err(format!(
+                "unknown JSON-RPC version {s:?} (expected \"2.0\" or \"1.0\")"
+            )),
+        }
+    }
+}
+
+// Reads the last used id from `path` and records `count` more ids as used,
+// holding an exclusive lock so that concurrent invocations never share ids
+fn reserve_ids(path: &std::path::Path, count: usize) -> crate::Result<usize> {
+    use std::io::{Read, Seek, Write};
+
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| crate::Error::new(format!("failed to open {}: {e}", path.display())))?;
+    file.lock()?;
+
+    let mut text = String::new();
+    file.read_to_string(&mut text)?;
+    let first_id = match text.trim() {
+        "" => 0,
+        last_id => {
+            let last_id: usize = last_id.parse().map_err(|e| {
+                crate::Error::new(format!("invalid id state in {}: {e}", path.display()))
+            })?;
+            last_id + 1
+        }
+    };
+
+    file.set_len(0)?;
+    file.rewind()?;
+    writeln!(file, "{}", first_id + count - 1)?;
+    Ok(first_id)
+}
+
+pub(crate) fn parse_params(text: &str) -> crate::Result<nojson::RawJsonOwned> {
+    Ok(parse_params_json(&read_params_text(text)?)?)
+}
+
+// With `--json-errors`, a JSON object locating the error is printed as a bare line to stderr
+// and the process exits with a non-zero status (instead of the usual `Error: ...` output)
+//
+// Called after the options are taken, because errors returned while taking an option are
+// wrapped in an "argument ... has an invalid value" message by `noargs`
+fn parse_error(
+    option: &str,
+    text: &str,
+    error: nojson::JsonParseError,
+    json_errors: bool,
+) -> crate::Error {
+    if !json_errors {
+        return error.into();
+    }
+    let location = error.get_line_and_column_numbers(text);
+    let report = nojson::object(|f| {
+        f.member("option", option)?;
+        f.member("message", error.to_string())?;
+        f.member("position", error.position())?;
+        f.member("line", location.map(|(line, _)| line.get()))?;
+        f.member("column", location.map(|(_, column)| column.get()))?;
+        f.member("snippet", error.get_line(text))
+    });
+    eprintln!("{report}");
+    std::process::exit(1);
+}
+
+// `@PATH` loads the params from a file (`@@` escapes a literal leading '@')
+fn read_params_text(text: &str) -> crate::Result<String> {
+    if let Some(text) = text.strip_prefix("@@") {
+        Ok(format!("@{text}"))
+    } else if let Some(path) = text.strip_prefix('@') {
+        std::fs::read_to_string(path)
+            .map_err(|e| crate::Error::new(format!("failed to read {path}: {e}")))
+    } else {
+        Ok(text.to_owned())
+    }
+}
+
+// Replaces each `${VAR}` with the value of the environment variable `VAR`
+fn expand_env_vars(text: &str, default: Option<&str>) -> crate::Result<String> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find('}') else {
+            return Err(crate::Error::new(format!(
+                "unterminated variable reference {:?}",
+                &rest[start..]
+            )));
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        match (std::env::var(name), default) {
+            (Ok(value), _) => expanded.push_str(&value),
+            (Err(std::env::VarError::NotPresent), Some(default)) => expanded.push_str(default),
+            (Err(e), _) => {
+                return Err(crate::Error::new(format!(
+                    "failed to expand environment variable {name:?}: {e}"
+                )));
+            }
+        }
+        rest = &rest[start + 2 + len + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+// Rewrites unquoted object keys and single-quoted strings into strict JSON
+// (anything else is copied as is and left for the JSON parser to reject)
+fn normalize_relaxed_json(text: &str) -> String {
+    let mut normalized = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                normalized.push(c);
+                while let Some(c) = chars.next() {
+                    normalized.push(c);
+                    if c == '\\' {
+                        normalized.extend(chars.next());
+                    } else if c == '"' {
+                        break;
+                    }
+                }
+            }
+            '\'' => {
+                normalized.push('"');
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' if chars.peek() == Some(&'\'') => {
+                            normalized.push('\'');
+                            chars.next();
+                        }
+                        '\\' => {
+                            normalized.push(c);
+                            normalized.extend(chars.next());
+                        }
+                        '"' => normalized.push_str("\\\""),
+                        '\'' => {
+                            normalized.push('"');
+                            break;
+                        }
+                        _ => normalized.push(c),
+                    }
+                }
+            }
+            c if c.is_ascii_alphabetic() || c == '_' || c == '$' => {
+                let mut identifier = c.to_string();
+                while let Some(&c) = chars.peek()
+                    && (c.is_ascii_alphanumeric() || c == '_' || c == '$')
+                {
+                    identifier.push(c);
+                    chars.next();
+                }
+                let mut whitespace = String::new();
+                while let Some(&c) = chars.peek()
+                    && c.is_whitespace()
+                {
+                    whitespace.push(c);
+                    chars.next();
+                }
+                if chars.peek() == Some(&':') {
+                    normalized.push('"');
+                    normalized.push_str(&identifier);
+                    normalized.push('"');
+                } else {
+                    // Literals such as `true` and `null`
+                    normalized.push_str(&identifier);
+                }
+                normalized.push_str(&whitespace);
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                // Keeps exponents such as `1e5` from being taken as identifiers
+                normalized.push(c);
+                while let Some(&c) = chars.peek()
+                    && (c.is_ascii_alphanumeric() || c == '.' || c == '+' || c == '-')
+                {
+                    normalized.push(c);
+                    chars.next();
+                }
+            }
+            _ => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+fn parse_params_json(text: &str) -> Result<nojson::RawJsonOwned, nojson::JsonParseError> {
+    let json = nojson::RawJson::parse(text)?;
+    if !matches!(
+        json.value().kind(),
+        nojson::JsonValueKind::Array | nojson::JsonValueKind::Object
+    ) {
+        return Err(json.value().invalid("must be a JSON array or JSON object"));
+    }
+    Ok(json.into_owned())
+}
+
+fn parse_params_array(text: &str) -> Result<Vec<nojson::RawJsonOwned>, nojson::JsonParseError> {
+    let json = parse_params_json(text)?;
+    if json.value().kind() != nojson::JsonValueKind::Array {
+        return Err(json.value().invalid("must be a JSON array"));
+    }
+    let mut params_array = Vec::new();
+    for element in json.value().to_array()? {
+        if !matches!(
+            element.kind(),
+            nojson::JsonValueKind::Array | nojson::JsonValueKind::Object
+        ) {
+            return Err(element.invalid("elements must be JSON arrays or JSON objects"));
+        }
+        params_array.push(nojson::RawJsonOwned::try_from(element)?);
+    }
+    if params_array.is_empty() {
+        return Err(json.value().invalid("must not be empty"));
+    }
+    Ok(params_array)
+}
+
+pub(crate) fn request_json<'a>(
+    method: &'a str,
+    params: Option<&'a nojson::RawJsonOwned>,
+    id: Option<usize>,
+) -> impl 'a + nojson::DisplayJson + std::fmt::Display {
+    nojson::object(move |f| {
+        f.member("jsonrpc", "2.0")?;
+        f.member("method", method)?;
+        if let Some(params) = params {
+            f.member("params", params)?;
+        }
+        if let Some(id) = id {
+            f.member("id", id)?;
+        }
+        Ok(())
+    })
+}
+
+// JSON-RPC 1.0 has no "jsonrpc" member, requires "params" and marks notifications with a null id
+fn request_json_v1<'a>(
+    method: &'a str,
+    params: Option<&'a nojson::RawJsonOwned>,
+    id: Option<usize>,
+) -> impl 'a + nojson::DisplayJson + std::fmt::Display {
+    nojson::object(move |f| {
+        f.member("method", method)?;
+        match params {
+            Some(params) => f.member("params", params)?,
+            None => f.member("params", nojson::array(|_| Ok(())))?,
+        }
+        f.member("id", id)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNSET: &str = "JLOU_TEST_SURELY_UNSET_VARIABLE";
+
+    #[test]
+    fn expand_env_vars_substitutes_variables() {
+        // `cargo test` sets `CARGO_PKG_NAME` in the environment of the test process
+        assert_eq!(
+            expand_env_vars(r#"{"name": "${CARGO_PKG_NAME}", "$x": "{}"}"#, None)
+                .map_err(|e| e.reason),
+            Ok(r#"{"name": "jlou", "$x": "{}"}"#.to_owned())
+        );
+        assert_eq!(
+            expand_env_vars(&format!("[${{{UNSET}}}]"), Some("null")).map_err(|e| e.reason),
+            Ok("[null]".to_owned())
+        );
+        assert!(expand_env_vars(&format!("[${{{UNSET}}}]"), None).is_err());
+    }
+
+    #[test]
+    fn expand_env_vars_rejects_unterminated_references() {
+        assert_eq!(
+            expand_env_vars(r#"["${CARGO_PKG_NAME"]"#, Some("")).map_err(|e| e.reason),
+            Err(r#"unterminated variable reference "${CARGO_PKG_NAME\"]""#.to_owned())
+        );
+        assert!(expand_env_vars("[1, ${", Some("")).is_err());
+    }
+
+    #[test]
+    fn normalize_relaxed_json_quotes_keys_and_strings() {
+        assert_eq!(
+            normalize_relaxed_json(r#"{a: 1, _b$ : 'x', "c": true, d: [null, -1e5]}"#),
+            r#"{"a": 1, "_b$" : "x", "c": true, "d": [null, -1e5]}"#
+        );
+        assert_eq!(
+            normalize_relaxed_json(r#"{'k': 'it\'s a "quote"', s: 'a\nb'}"#),
+            r#"{"k": "it's a \"quote\"", "s": "a\nb"}"#
+        );
+    }
+
+    #[test]
+    fn normalize_relaxed_json_keeps_double_quoted_strings() {
+        let text = r#"{"a: 'b'": "{c: 'd'} \" e: 'f'", "\\": 1}"#;
+        assert_eq!(normalize_relaxed_json(text), text);
+    }
+}
@@ -24,7 +24,8 @@ impl std::fmt::Debug for Error {
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.reason)?;
-        if self.backtrace.status() == BacktraceStatus::Captured {
+        // The alternate form (`{:#}`) omits the backtrace
+        if !f.alternate() && self.backtrace.status() == BacktraceStatus::Captured {
             write!(f, "\n\nBacktrace:\n{}", self.backtrace)?;
         }
         Ok(())
@@ -0,0 +1,277 @@
+use std::io::{BufRead, Write};
+
+/// How multiple JSON-RPC messages are delimited within a byte stream or a UDP packet.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// JSON Lines: messages are joined with '\n'.
+    #[default]
+    Lines,
+
+    /// LSP / MCP style: each message is preceded by a `Content-Length: N\r\n\r\n` header.
+    ContentLength,
+}
+
+impl Framing {
+    /// Returns the number of bytes that `encode()` would append to `buf` for a message of `message_len` bytes.
+    pub fn encoded_len(self, buf: &[u8], message_len: usize) -> usize {
+        match self {
+            Framing::Lines => usize::from(!buf.is_empty()) + message_len,
+            Framing::ContentLength => content_length_header(message_len).len() + message_len,
+        }
+    }
+
+    pub fn encode(self, buf: &mut Vec<u8>, message: &[u8]) {
+        match self {
+            Framing::Lines => {
+                if !buf.is_empty() {
+                    buf.push(b'\n');
+                }
+            }
+            Framing::ContentLength => {
+                buf.extend_from_slice(content_length_header(message.len()).as_bytes());
+            }
+        }
+        buf.extend_from_slice(message);
+    }
+
+    /// Writes a single message to a byte stream (unlike `encode()`, a trailing '\n' is always emitted for `Lines`).
+    pub fn write_message<W: Write>(self, writer: &mut W, message: &[u8]) -> std::io::Result<()> {
+        match self {
+            Framing::Lines => {
+                writer.write_all(message)?;
+                writer.write_all(b"\n")
+            }
+            Framing::ContentLength => {
+                writer.write_all(content_length_header(message.len()).as_bytes())?;
+                writer.write_all(message)
+            }
+        }
+    }
+
+    /// Reads the next message from `reader`, returning `Ok(None)` at EOF.
+    pub fn read_message<R: BufRead>(self, reader: &mut R) -> std::io::Result<Option<String>> {
+        self.read_message_with_limit(reader, usize::MAX)
+    }
+
+    /// Like `read_message()`, but fails without buffering the whole message if it exceeds `limit` bytes.
+    pub fn read_message_with_limit<R: BufRead>(
+        self,
+        reader: &mut R,
+        limit: usize,
+    ) -> std::io::Result<Option<String>> {
+        let too_large = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("message exceeds the size limit of {limit} bytes"),
+            )
+        };
+        match self {
+            Framing::Lines => {
+                let mut line = String::new();
+                let max_read = (limit as u64).saturating_add(2); // Including "\r\n"
+                if std::io::Read::take(&mut *reader, max_read).read_line(&mut line)? == 0 {
+                    return Ok(None);
+                }
+                if line.ends_with('\n') {
+                    line.pop();
+                    if line.ends_with('\r') {
+                        line.pop();
+                    }
+                }
+                if line.len() > limit {
+                    return Err(too_large());
+                }
+                Ok(Some(line))
+            }
+            Framing::ContentLength => {
+                let Some(content_length) = read_content_length_headers(reader)? else {
+                    return Ok(None);
+                };
+                if content_length > limit {
+                    return Err(too_large());
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body)?;
+                String::from_utf8(body)
+                    .map(Some)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+            }
+        }
+    }
+
+    /// Like `read_message_with_limit()`, but also returns the bytes consumed from `reader`
+    /// (including the framing, e.g. the `Content-Length` header).
+    pub fn read_raw_message_with_limit<R: BufRead>(
+        self,
+        reader: &mut R,
+        limit: usize,
+    ) -> std::io::Result<Option<(String, Vec<u8>)>> {
+        let mut reader = CapturingReader {
+            inner: reader,
+            captured: Vec::new(),
+        };
+        let message = self.read_message_with_limit(&mut reader, limit)?;
+        Ok(message.map(|message| (message, reader.captured)))
+    }
+}
+
+impl std::str::FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lines" => Ok(Framing::Lines),
+            "content-length" => Ok(Framing::ContentLength),
+            _ => Err(format!(
+                "unknown framing {s:?} (expected \"lines\" or \"content-length\")"
+            )),
+        }
+    }
+}
+
+// Keeps a copy of every byte read through it
+struct CapturingReader<'a, R> {
+    inner: &'a mut R,
+    captured: Vec<u8>,
+}
+
+impl<R: BufRead> std::io::Read for CapturingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.inner.read(buf)?;
+        self.captured.extend_from_slice(&buf[..size]);
+        Ok(size)
+    }
+}
+
+impl<R: BufRead> BufRead for CapturingReader<'_, R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        // `amount` bytes are already buffered, so this does not block
+        if amount > 0
+            && let Ok(buf) = self.inner.fill_buf()
+        {
+            self.captured
+                .extend_from_slice(&buf[..amount.min(buf.len())]);
+        }
+        self.inner.consume(amount);
+    }
+}
+
+fn content_length_header(content_length: usize) -> String {
+    format!("Content-Length: {content_length}\r\n\r\n")
+}
+
+fn read_content_length_headers<R: BufRead>(reader: &mut R) -> std::io::Result<Option<usize>> {
+    let invalid_data =
+        |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+
+    let mut content_length = None;
+    let mut has_headers = false;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            if !has_headers {
+                return Ok(None);
+            }
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            if !has_headers {
+                // Tolerates stray blank lines between messages
+                continue;
+            }
+            break;
+        }
+        has_headers = true;
+
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(invalid_data("malformed header line"));
+        };
+        if name.trim().eq_ignore_ascii_case("content-length") {
+            let value = value
+                .trim()
+                .parse()
+                .map_err(|_| invalid_data("invalid Content-Length header value"))?;
+            content_length = Some(value);
+        }
+    }
+
+    content_length
+        .map(Some)
+        .ok_or_else(|| invalid_data("missing Content-Length header"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(framing: Framing, input: &[u8]) -> std::io::Result<Vec<String>> {
+        let mut reader = std::io::BufReader::new(input);
+        let mut messages = Vec::new();
+        while let Some(message) = framing.read_message(&mut reader)? {
+            messages.push(message);
+        }
+        Ok(messages)
+    }
+
+    #[test]
+    fn content_length_round_trip() {
+        let long_message = format!("[{}]", "1,".repeat(600) + "1");
+        let mut buf = Vec::new();
+        for message in ["{}", "[1,2,3]", long_message.as_str()] {
+            let before = buf.len();
+            Framing::ContentLength.encode(&mut buf, message.as_bytes());
+            assert_eq!(
+                Framing::ContentLength.encoded_len(&[], message.len()),
+                buf.len() - before
+            );
+        }
+        assert!(buf.starts_with(b"Content-Length: 2\r\n\r\n{}Content-Length: 7\r\n\r\n"));
+        assert_eq!(
+            read_all(Framing::ContentLength, &buf).unwrap(),
+            ["{}", "[1,2,3]", long_message.as_str()]
+        );
+    }
+
+    #[test]
+    fn content_length_headers() {
+        // Other headers and stray blank lines between messages are ignored
+        let input = b"\r\nContent-Type: application/json\r\ncontent-length:  11 \r\n\r\n{\"a\":\"\\\"}\"}\n\n";
+        assert_eq!(
+            read_all(Framing::ContentLength, input).unwrap(),
+            ["{\"a\":\"\\\"}\"}"]
+        );
+
+        for input in [
+            &b"Content-Length: 10\r\n\r\n{}"[..],
+            b"Content-Length: x\r\n\r\n{}",
+            b"Content-Type: application/json\r\n\r\n{}",
+            b"Content-Length 2\r\n\r\n{}",
+        ] {
+            assert!(read_all(Framing::ContentLength, input).is_err());
+        }
+    }
+
+    #[test]
+    fn content_length_limit() {
+        let mut reader = std::io::BufReader::new(&b"Content-Length: 100\r\n\r\n"[..]);
+        let e = Framing::ContentLength
+            .read_message_with_limit(&mut reader, 99)
+            .unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+
+        let input = b"Content-Length: 2\r\n\r\n{}";
+        let mut reader = std::io::BufReader::new(&input[..]);
+        let (message, raw) = Framing::ContentLength
+            .read_raw_message_with_limit(&mut reader, 2)
+            .unwrap()
+            .unwrap();
+        assert_eq!(message, "{}");
+        assert_eq!(raw, input);
+    }
+}
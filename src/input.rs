@@ -0,0 +1,338 @@
+use std::collections::VecDeque;
+use std::io::BufRead;
+
+/// Format of the JSON-RPC requests read from standard input.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    /// Detects the format from the first non-blank line:
+    /// `Ndjson` if the line is a complete JSON value, `Json` otherwise.
+    #[default]
+    Auto,
+
+    /// One request per line (JSON Lines).
+    /// Lines holding a JSON array are treated as sequences of requests.
+    Ndjson,
+
+    /// Arbitrarily formatted (e.g., pretty-printed) JSON values.
+    /// Top-level arrays are treated as sequences of requests.
+    Json,
+
+    /// Concatenated MessagePack values, converted to JSON.
+    /// Top-level arrays are treated as sequences of requests.
+    #[cfg(feature = "msgpack")]
+    Msgpack,
+}
+
+impl std::str::FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(InputFormat::Auto),
+            "ndjson" => Ok(InputFormat::Ndjson),
+            "json" => Ok(InputFormat::Json),
+            #[cfg(feature = "msgpack")]
+            "msgpack" => Ok(InputFormat::Msgpack),
+            #[cfg(not(feature = "msgpack"))]
+            "msgpack" => {
+                Err("msgpack requires building with the `msgpack` cargo feature".to_owned())
+            }
+            _ => Err(format!(
+                "unknown input format {s:?} (expected \"auto\", \"ndjson\" or \"json\")"
+            )),
+        }
+    }
+}
+
+/// Iterator yielding each request of the input as single-line JSON text.
+#[derive(Debug)]
+pub struct RequestReader<R> {
+    reader: R,
+    format: InputFormat,
+    allow_comments: bool,
+    max_line_length: usize,
+    pending: VecDeque<String>,
+}
+
+impl<R: BufRead> RequestReader<R> {
+    pub fn new(reader: R, format: InputFormat) -> Self {
+        Self {
+            reader,
+            format,
+            allow_comments: false,
+            max_line_length: usize::MAX,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Skips whole lines starting with `#` or `//` (blank lines are always skipped).
+    pub fn allow_comments(mut self, allow: bool) -> Self {
+        self.allow_comments = allow;
+        self
+    }
+
+    /// Fails instead of buffering input lines longer than `max` bytes.
+    pub fn max_line_length(mut self, max: usize) -> Self {
+        self.max_line_length = max;
+        self
+    }
+
+    fn is_skipped_line(&self, line: &str) -> bool {
+        let line = line.trim_start();
+        line.is_empty()
+            || (self.allow_comments && (line.starts_with('#') || line.starts_with("//")))
+    }
+
+    fn read_line(&mut self) -> std::io::Result<Option<String>> {
+        loop {
+            let mut line = String::new();
+            if crate::utils::read_line_with_limit(
+                &mut self.reader,
+                &mut line,
+                self.max_line_length,
+            )? == 0
+            {
+                return Ok(None);
+            }
+            if line.ends_with('\n') {
+                line.pop();
+                if line.ends_with('\r') {
+                    line.pop();
+                }
+            }
+            if !self.is_skipped_line(&line) {
+                return Ok(Some(line));
+            }
+        }
+    }
+
+    fn detect_format(&mut self) -> crate::Result<()> {
+        let Some(mut first_line) = self.read_line()? else {
+            self.format = InputFormat::Ndjson;
+            return Ok(());
+        };
+
+        if nojson::RawJson::parse(&first_line).is_ok() {
+            self.format = InputFormat::Ndjson;
+            self.push_line(first_line)?;
+        } else {
+            self.format = InputFormat::Json;
+            first_line.push('\n');
+            self.read_json_document(first_line)?;
+        }
+        Ok(())
+    }
+
+    // Lines holding a JSON array are expanded into their elements
+    fn push_line(&mut self, line: String) -> crate::Result<()> {
+        if !line.trim_start().starts_with('[') {
+            self.pending.push_back(line);
+            return Ok(());
+        }
+        let json = nojson::RawJson::parse(&line)?;
+        for element in json.value().to_array()? {
+            self.pending.push_back(compact(element));
+        }
+        Ok(())
+    }
+
+    // `--max-line-length` applies to each line, not to the whole document
+    fn read_json_document(&mut self, mut text: String) -> crate::Result<()> {
+        loop {
+            let mut line = String::new();
+            if crate::utils::read_line_with_limit(
+                &mut self.reader,
+                &mut line,
+                self.max_line_length,
+            )? == 0
+            {
+                break;
+            }
+            text.push_str(&line);
+        }
+        if self.allow_comments {
+            // JSON strings cannot contain raw newlines, so whole-line comments are unambiguous
+            text = text
+                .lines()
+                .filter(|line| !self.is_skipped_line(line))
+                .collect::<Vec<_>>()
+                .join("\n");
+        }
+        for value_text in split_json_values(&text)? {
+            let json = nojson::RawJson::parse(value_text)?;
+            if json.value().kind() == nojson::JsonValueKind::Array {
+                for element in json.value().to_array()? {
+                    self.pending.push_back(compact(element));
+                }
+            } else {
+                self.pending.push_back(compact(json.value()));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Iterator for RequestReader<R> {
+    type Item = crate::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = match self.format {
+            InputFormat::Auto => self.detect_format(),
+            // Reads the whole input at once (subsequent calls will observe EOF)
+            InputFormat::Json if self.pending.is_empty() => self.read_json_document(String::new()),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            return Some(Err(e));
+        }
+
+        loop {
+            if let Some(line) = self.pending.pop_front() {
+                return Some(Ok(line));
+            }
+            #[cfg(feature = "msgpack")]
+            if self.format == InputFormat::Msgpack {
+                let line = match crate::msgpack::read_value_as_json(&mut self.reader) {
+                    Ok(Some(line)) => line,
+                    Ok(None) => return None,
+                    Err(e) => return Some(Err(e)),
+                };
+                if let Err(e) = self.push_line(line) {
+                    return Some(Err(e));
+                }
+                continue;
+            }
+            let line = match self.read_line() {
+                Ok(Some(line)) => line,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if let Err(e) = self.push_line(line) {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+fn compact(value: nojson::RawJsonValue<'_, '_>) -> String {
+    nojson::json(|f| f.value(value)).to_string()
+}
+
+// Splits a text containing zero or more concatenated JSON values (e.g., pretty-printed objects)
+fn split_json_values(text: &str) -> crate::Result<Vec<&str>> {
+    let mut values = Vec::new();
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+
+        match c {
+            '"' if depth > 0 => in_string = true,
+            '{' | '[' => {
+                if depth == 0 {
+                    start = Some(i);
+                }
+                depth += 1;
+            }
+            '}' | ']' => {
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| crate::Error::new("unbalanced brackets in JSON input"))?;
+                if depth == 0
+                    && let Some(start) = start.take()
+                {
+                    values.push(&text[start..=i]);
+                }
+            }
+            c if depth == 0 && !c.is_whitespace() => {
+                return Err(crate::Error::new(format!(
+                    "unexpected character {c:?} outside of a JSON object or array"
+                )));
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 || in_string {
+        return Err(crate::Error::new("unexpected end of JSON input"));
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(
+        input: &str,
+        format: InputFormat,
+        max_line_length: usize,
+    ) -> crate::Result<Vec<String>> {
+        RequestReader::new(input.as_bytes(), format)
+            .max_line_length(max_line_length)
+            .collect()
+    }
+
+    #[test]
+    fn max_line_length_applies_to_each_line_of_a_json_document() {
+        let input = (0..100)
+            .map(|i| format!("  {{\"jsonrpc\": \"2.0\", \"method\": \"m\", \"id\": {i}}}"))
+            .collect::<Vec<_>>()
+            .join(",\n");
+        let input = format!("[\n{input}\n]\n");
+        assert!(input.len() > 1000);
+
+        let requests = read_all(&input, InputFormat::Json, 100).expect("each line is short");
+        assert_eq!(requests.len(), 100);
+        assert_eq!(requests[99], r#"{"jsonrpc":"2.0","method":"m","id":99}"#);
+        assert_eq!(
+            read_all(&input, InputFormat::Auto, 100)
+                .map(|r| r.len())
+                .ok(),
+            Some(100)
+        );
+        assert!(read_all(&input, InputFormat::Json, 40).is_err());
+    }
+
+    #[test]
+    fn split_json_values_handles_strings() {
+        let text = r#"
+{"a": "}{", "b": "\"]"}
+[1, "\\", {"c": "[{"}]
+{}
+"#;
+        assert_eq!(
+            split_json_values(text).map_err(|e| e.reason),
+            Ok(vec![
+                r#"{"a": "}{", "b": "\"]"}"#,
+                r#"[1, "\\", {"c": "[{"}]"#,
+                "{}"
+            ])
+        );
+        assert_eq!(split_json_values(" \n").map_err(|e| e.reason), Ok(vec![]));
+    }
+
+    #[test]
+    fn split_json_values_rejects_malformed_input() {
+        for text in [
+            r#"{"a": "}"#,
+            "[[]",
+            r#"{"a": "\"}"#,
+            "{}]",
+            "{} 1",
+            r#""a""#,
+        ] {
+            assert!(split_json_values(text).is_err(), "{text:?}");
+        }
+    }
+}
@@ -1,9 +1,19 @@
 pub mod command_call;
+pub mod command_check;
 pub mod command_echo_server;
+pub mod command_features;
+pub mod command_order_check;
+pub mod command_probe_mtu;
 pub mod command_req;
 mod error;
+mod framing;
+mod input;
+#[cfg(feature = "msgpack")]
+mod msgpack;
 mod utils;
+mod verbosity;
 
 pub use error::Error;
+pub use verbosity::Verbosity;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -8,10 +8,31 @@ fn main() -> noargs::Result<()> {
         return Ok(());
     }
     noargs::HELP_FLAG.take_help(&mut args);
+    let verbosity = jlou::Verbosity::take(&mut args)?;
 
-    let _ = jlou::command_call::try_run(&mut args)?
-        || jlou::command_req::try_run(&mut args)?
-        || jlou::command_echo_server::try_run(&mut args)?;
+    let result = run(args, verbosity);
+    if let Err(e) = &result
+        && verbosity.is_json()
+    {
+        // Reported as a log line instead of the plain text printed when `main()` fails
+        let message = match e {
+            noargs::Error::Other { error, .. } => format!("{error:#}"),
+            e => format!("{e:?}"),
+        };
+        verbosity.error(message);
+        std::process::exit(1);
+    }
+    result
+}
+
+fn run(mut args: noargs::RawArgs, verbosity: jlou::Verbosity) -> noargs::Result<()> {
+    let _ = jlou::command_call::try_run(&mut args, verbosity)?
+        || jlou::command_req::try_run(&mut args, verbosity)?
+        || jlou::command_echo_server::try_run(&mut args, verbosity)?
+        || jlou::command_check::try_run(&mut args, verbosity)?
+        || jlou::command_probe_mtu::try_run(&mut args, verbosity)?
+        || jlou::command_order_check::try_run(&mut args, verbosity)?
+        || jlou::command_features::try_run(&mut args)?;
 
     if let Some(help) = args.finish()? {
         print!("{help}");
@@ -0,0 +1,273 @@
+//! Minimal MessagePack <-> JSON conversion for `call --format msgpack` / `--output-format msgpack`.
+//!
+//! Only the types that have a JSON counterpart are supported (no binary or extension types),
+//! and map keys must be strings.
+use std::io::Read;
+
+// Nesting limit of decoded values (deeper inputs are rejected instead of overflowing the stack)
+const MAX_DEPTH: usize = 512;
+
+/// Reads the next MessagePack value from `reader` and returns it as single-line JSON text.
+///
+/// Returns `Ok(None)` if `reader` is at EOF.
+pub fn read_value_as_json<R: std::io::BufRead>(reader: &mut R) -> crate::Result<Option<String>> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let mut json = String::new();
+    decode(reader, &mut json, 0)?;
+    Ok(Some(json))
+}
+
+fn decode<R: Read>(reader: &mut R, json: &mut String, depth: usize) -> crate::Result<()> {
+    if depth > MAX_DEPTH {
+        return Err(crate::Error::new("MessagePack value is nested too deeply"));
+    }
+    let marker = read_u8(reader)?;
+    match marker {
+        0x00..=0x7f => push_json(json, marker),
+        0x80..=0x8f => decode_map(reader, json, usize::from(marker & 0x0f), depth)?,
+        0x90..=0x9f => decode_array(reader, json, usize::from(marker & 0x0f), depth)?,
+        0xa0..=0xbf => decode_str(reader, json, usize::from(marker & 0x1f))?,
+        0xc0 => json.push_str("null"),
+        0xc2 => json.push_str("false"),
+        0xc3 => json.push_str("true"),
+        0xca => push_float(json, f64::from(f32::from_be_bytes(read_array(reader)?)))?,
+        0xcb => push_float(json, f64::from_be_bytes(read_array(reader)?))?,
+        0xcc => push_json(json, read_u8(reader)?),
+        0xcd => push_json(json, u16::from_be_bytes(read_array(reader)?)),
+        0xce => push_json(json, u32::from_be_bytes(read_array(reader)?)),
+        0xcf => push_json(json, u64::from_be_bytes(read_array(reader)?)),
+        0xd0 => push_json(json, i8::from_be_bytes(read_array(reader)?)),
+        0xd1 => push_json(json, i16::from_be_bytes(read_array(reader)?)),
+        0xd2 => push_json(json, i32::from_be_bytes(read_array(reader)?)),
+        0xd3 => push_json(json, i64::from_be_bytes(read_array(reader)?)),
+        0xd9 => {
+            let len = read_u8(reader)?;
+            decode_str(reader, json, usize::from(len))?
+        }
+        0xda => {
+            let len = u16::from_be_bytes(read_array(reader)?);
+            decode_str(reader, json, usize::from(len))?
+        }
+        0xdb => {
+            let len = u32::from_be_bytes(read_array(reader)?);
+            decode_str(reader, json, len as usize)?
+        }
+        0xdc => {
+            let len = u16::from_be_bytes(read_array(reader)?);
+            decode_array(reader, json, usize::from(len), depth)?
+        }
+        0xdd => {
+            let len = u32::from_be_bytes(read_array(reader)?);
+            decode_array(reader, json, len as usize, depth)?
+        }
+        0xde => {
+            let len = u16::from_be_bytes(read_array(reader)?);
+            decode_map(reader, json, usize::from(len), depth)?
+        }
+        0xdf => {
+            let len = u32::from_be_bytes(read_array(reader)?);
+            decode_map(reader, json, len as usize, depth)?
+        }
+        0xe0..=0xff => push_json(json, marker as i8),
+        0xc4..=0xc6 => {
+            return Err(crate::Error::new(
+                "MessagePack binary values cannot be converted to JSON",
+            ));
+        }
+        0xc7..=0xc9 | 0xd4..=0xd8 => {
+            return Err(crate::Error::new(
+                "MessagePack extension values cannot be converted to JSON",
+            ));
+        }
+        0xc1 => return Err(crate::Error::new("invalid MessagePack marker 0xc1")),
+    }
+    Ok(())
+}
+
+fn decode_array<R: Read>(
+    reader: &mut R,
+    json: &mut String,
+    len: usize,
+    depth: usize,
+) -> crate::Result<()> {
+    json.push('[');
+    for i in 0..len {
+        if i > 0 {
+            json.push(',');
+        }
+        decode(reader, json, depth + 1)?;
+    }
+    json.push(']');
+    Ok(())
+}
+
+fn decode_map<R: Read>(
+    reader: &mut R,
+    json: &mut String,
+    len: usize,
+    depth: usize,
+) -> crate::Result<()> {
+    json.push('{');
+    for i in 0..len {
+        if i > 0 {
+            json.push(',');
+        }
+        let key_start = json.len();
+        decode(reader, json, depth + 1)?;
+        if !json[key_start..].starts_with('"') {
+            return Err(crate::Error::new("MessagePack map keys must be strings"));
+        }
+        json.push(':');
+        decode(reader, json, depth + 1)?;
+    }
+    json.push('}');
+    Ok(())
+}
+
+fn decode_str<R: Read>(reader: &mut R, json: &mut String, len: usize) -> crate::Result<()> {
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(unexpected_eof());
+    }
+    let s = String::from_utf8(bytes)
+        .map_err(|e| crate::Error::new(format!("invalid UTF-8 in MessagePack string: {e}")))?;
+    push_json(json, s);
+    Ok(())
+}
+
+fn push_json<T: nojson::DisplayJson>(json: &mut String, value: T) {
+    json.push_str(&nojson::Json(value).to_string());
+}
+
+fn push_float(json: &mut String, value: f64) -> crate::Result<()> {
+    if !value.is_finite() {
+        return Err(crate::Error::new(format!(
+            "MessagePack float {value} cannot be converted to JSON"
+        )));
+    }
+    push_json(json, value);
+    Ok(())
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> crate::Result<u8> {
+    let [byte] = read_array(reader)?;
+    Ok(byte)
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> crate::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            unexpected_eof()
+        } else {
+            e.into()
+        }
+    })?;
+    Ok(bytes)
+}
+
+fn unexpected_eof() -> crate::Error {
+    crate::Error::new("unexpected end of MessagePack input")
+}
+
+/// Appends the MessagePack encoding of `value` to `buf`.
+///
+/// Integers outside the 64-bit range are encoded as floats.
+pub fn encode_json(value: nojson::RawJsonValue<'_, '_>, buf: &mut Vec<u8>) -> crate::Result<()> {
+    match value.kind() {
+        nojson::JsonValueKind::Null => buf.push(0xc0),
+        nojson::JsonValueKind::Boolean => {
+            buf.push(if value.as_boolean_str()? == "true" {
+                0xc3
+            } else {
+                0xc2
+            });
+        }
+        nojson::JsonValueKind::Integer => {
+            if let Ok(n) = value.as_raw_str().parse::<i64>() {
+                encode_int(n, buf);
+            } else if let Ok(n) = value.as_raw_str().parse::<u64>() {
+                buf.push(0xcf);
+                buf.extend_from_slice(&n.to_be_bytes());
+            } else {
+                encode_float(value.as_raw_str().parse()?, buf);
+            }
+        }
+        nojson::JsonValueKind::Float => encode_float(value.as_raw_str().parse()?, buf),
+        nojson::JsonValueKind::String => encode_str(&value.to_unquoted_string_str()?, buf),
+        nojson::JsonValueKind::Array => {
+            let elements: Vec<_> = value.to_array()?.collect();
+            encode_len(elements.len(), [0x90, 0xdc, 0xdd], buf);
+            for element in elements {
+                encode_json(element, buf)?;
+            }
+        }
+        nojson::JsonValueKind::Object => {
+            let members: Vec<_> = value.to_object()?.collect();
+            encode_len(members.len(), [0x80, 0xde, 0xdf], buf);
+            for (name, value) in members {
+                encode_str(&name.to_unquoted_string_str()?, buf);
+                encode_json(value, buf)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn encode_int(n: i64, buf: &mut Vec<u8>) {
+    if let Ok(n) = u8::try_from(n)
+        && n <= 0x7f
+    {
+        buf.push(n);
+    } else if (-32..0).contains(&n) {
+        buf.push(n as u8);
+    } else if let Ok(n) = i8::try_from(n) {
+        buf.push(0xd0);
+        buf.extend_from_slice(&n.to_be_bytes());
+    } else if let Ok(n) = i16::try_from(n) {
+        buf.push(0xd1);
+        buf.extend_from_slice(&n.to_be_bytes());
+    } else if let Ok(n) = i32::try_from(n) {
+        buf.push(0xd2);
+        buf.extend_from_slice(&n.to_be_bytes());
+    } else {
+        buf.push(0xd3);
+        buf.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+fn encode_float(n: f64, buf: &mut Vec<u8>) {
+    buf.push(0xcb);
+    buf.extend_from_slice(&n.to_be_bytes());
+}
+
+fn encode_str(s: &str, buf: &mut Vec<u8>) {
+    if s.len() < 32 {
+        buf.push(0xa0 | s.len() as u8);
+    } else if let Ok(len) = u8::try_from(s.len()) {
+        buf.extend_from_slice(&[0xd9, len]);
+    } else if let Ok(len) = u16::try_from(s.len()) {
+        buf.push(0xda);
+        buf.extend_from_slice(&len.to_be_bytes());
+    } else {
+        buf.push(0xdb);
+        buf.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    }
+    buf.extend_from_slice(s.as_bytes());
+}
+
+// `markers` are the fix / 16-bit / 32-bit length variants of the array or map type
+fn encode_len(len: usize, markers: [u8; 3], buf: &mut Vec<u8>) {
+    if len < 16 {
+        buf.push(markers[0] | len as u8);
+    } else if let Ok(len) = u16::try_from(len) {
+        buf.push(markers[1]);
+        buf.extend_from_slice(&len.to_be_bytes());
+    } else {
+        buf.push(markers[2]);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
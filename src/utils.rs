@@ -1,33 +1,183 @@
-pub fn parse_socket_addr(s: &str) -> Result<std::net::SocketAddr, std::net::AddrParseError> {
+/// Parses `[IP_ADDR]:PORT` or `HOSTNAME:PORT` (hostnames are resolved to their first address).
+pub fn parse_socket_addr(s: &str) -> crate::Result<std::net::SocketAddr> {
     if s.starts_with(':') {
-        format!("127.0.0.1{s}").parse()
-    } else {
-        s.parse()
+        return Ok(format!("127.0.0.1{s}").parse()?);
+    }
+    if let Ok(addr) = s.parse() {
+        return Ok(addr);
+    }
+    std::net::ToSocketAddrs::to_socket_addrs(s)
+        .map_err(|e| crate::Error::new(format!("failed to resolve {s:?}: {e}")))?
+        .next()
+        .ok_or_else(|| crate::Error::new(format!("no addresses found for {s:?}")))
+}
+
+/// Takes all occurrences of a repeatable option.
+pub fn take_repeated_opt<F, T, E>(
+    spec: noargs::OptSpec,
+    args: &mut noargs::RawArgs,
+    mut f: F,
+) -> noargs::Result<Vec<T>>
+where
+    F: FnMut(&noargs::Opt) -> Result<T, E>,
+    E: std::fmt::Display,
+{
+    let mut values = Vec::new();
+    while let Some(value) = spec.take(args).present_and_then(|o| f(&o))? {
+        values.push(value);
+        if args.metadata().help_mode {
+            // In help mode, the same (example or default) value would be returned forever
+            break;
+        }
+    }
+    Ok(values)
+}
+
+/// Parses a non-negative number of seconds (negative, NaN and overflowing values are errors).
+pub fn parse_duration_secs(s: &str) -> Result<std::time::Duration, String> {
+    let secs: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    std::time::Duration::try_from_secs_f64(secs).map_err(|e| format!("{e}"))
+}
+
+/// Writes `line` followed by a newline, returning `Ok(false)` if the reading end of the pipe was closed.
+pub fn write_line<W, T>(writer: &mut W, line: T) -> std::io::Result<bool>
+where
+    W: std::io::Write,
+    T: std::fmt::Display,
+{
+    match writeln!(writer, "{line}") {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(false),
+        Err(e) => Err(e),
     }
 }
 
-pub fn parse_duration_secs(s: &str) -> Result<std::time::Duration, std::num::ParseFloatError> {
-    let secs = s.parse()?;
-    Ok(std::time::Duration::from_secs_f32(secs))
+/// Like `BufRead::read_line()`, but fails once the line exceeds `limit` bytes (excluding the line terminator).
+///
+/// Only the newly read line counts towards `limit`, not what `buf` already holds.
+pub fn read_line_with_limit<R: std::io::BufRead>(
+    reader: &mut R,
+    buf: &mut String,
+    limit: usize,
+) -> std::io::Result<usize> {
+    let start = buf.len();
+    let max_read = (limit as u64).saturating_add(2); // Including "\r\n"
+    let size = std::io::BufRead::read_line(&mut std::io::Read::take(&mut *reader, max_read), buf)?;
+    if buf[start..].trim_end_matches(['\r', '\n']).len() > limit {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("input line exceeds the limit of {limit} bytes"),
+        ));
+    }
+    Ok(size)
+}
+
+#[derive(Debug)]
+pub struct JsonRpcRequest<'text, 'raw> {
+    pub id: Option<nojson::RawJsonValue<'text, 'raw>>,
+    pub method: std::borrow::Cow<'text, str>,
+}
+
+/// JSON-RPC versions accepted in the `jsonrpc` member of messages.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptVersion {
+    /// Only "2.0" (the member is required).
+    #[default]
+    V2,
+
+    /// "2.0" or "1.0" (JSON-RPC 1.0 messages have no `jsonrpc` member, so it may be omitted).
+    V1,
+
+    /// Any version string, or no `jsonrpc` member at all.
+    Any,
+}
+
+impl AcceptVersion {
+    fn accepts(self, version: Option<&str>) -> bool {
+        match self {
+            AcceptVersion::V2 => version == Some("2.0"),
+            AcceptVersion::V1 => matches!(version, None | Some("2.0" | "1.0")),
+            AcceptVersion::Any => true,
+        }
+    }
+}
+
+impl std::str::FromStr for AcceptVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "2.0" => Ok(AcceptVersion::V2),
+            "1.0" => Ok(AcceptVersion::V1),
+            "any" => Ok(AcceptVersion::Any),
+            _ => Err(format!(
+                "unknown version {s:?} (expected \"2.0\", \"1.0\" or \"any\")"
+            )),
+        }
+    }
+}
+
+/// Letter case normalization applied to method names.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MethodCase {
+    /// Method names are used as is.
+    #[default]
+    AsIs,
+
+    /// Method names are converted to lowercase.
+    Lower,
+
+    /// Method names are converted to uppercase.
+    Upper,
+}
+
+impl MethodCase {
+    pub fn apply(self, method: &str) -> String {
+        match self {
+            MethodCase::AsIs => method.to_owned(),
+            MethodCase::Lower => method.to_lowercase(),
+            MethodCase::Upper => method.to_uppercase(),
+        }
+    }
+}
+
+impl std::str::FromStr for MethodCase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "asis" => Ok(MethodCase::AsIs),
+            "lower" => Ok(MethodCase::Lower),
+            "upper" => Ok(MethodCase::Upper),
+            _ => Err(format!(
+                "unknown method case {s:?} (expected \"asis\", \"lower\" or \"upper\")"
+            )),
+        }
+    }
 }
 
 pub fn validate_json_rpc_request<'text, 'raw>(
     value: nojson::RawJsonValue<'text, 'raw>,
-) -> Result<Option<nojson::RawJsonValue<'text, 'raw>>, nojson::JsonParseError> {
+    accept_version: AcceptVersion,
+) -> Result<JsonRpcRequest<'text, 'raw>, nojson::JsonParseError> {
     if value.kind() == nojson::JsonValueKind::Array {
         return Err(value.invalid("batch requests are not supported"));
     }
 
-    let mut has_jsonrpc = false;
-    let mut has_method = false;
+    let mut jsonrpc = None;
+    let mut method = None;
     let mut id = None;
     for (name, value) in value.to_object()? {
         match name.as_string_str()? {
             "jsonrpc" => {
-                if value.as_string_str()? != "2.0" {
+                let version = value.as_string_str()?;
+                if accept_version == AcceptVersion::V2 && version != "2.0" {
                     return Err(value.invalid("jsonrpc version must be '2.0'"));
                 }
-                has_jsonrpc = true;
+                if !accept_version.accepts(Some(version)) {
+                    return Err(value.invalid("jsonrpc version must be '2.0' or '1.0'"));
+                }
+                jsonrpc = Some(version);
             }
             "id" => {
                 if !matches!(
@@ -42,15 +192,15 @@ pub fn validate_json_rpc_request<'text, 'raw>(
                 if value.kind() != nojson::JsonValueKind::String {
                     return Err(value.invalid("method must be a string"));
                 }
-                has_method = true;
+                method = Some(value.to_unquoted_string_str()?);
             }
-            "params" => {
+            "params"
                 if !matches!(
                     value.kind(),
                     nojson::JsonValueKind::Object | nojson::JsonValueKind::Array
-                ) {
-                    return Err(value.invalid("params must be an object or array"));
-                }
+                ) =>
+            {
+                return Err(value.invalid("params must be an object or array"));
             }
             _ => {
                 // Ignore unknown members
@@ -58,12 +208,675 @@ pub fn validate_json_rpc_request<'text, 'raw>(
         }
     }
 
-    if !has_jsonrpc {
+    if jsonrpc.is_none() && !accept_version.accepts(None) {
         return Err(value.invalid("jsonrpc field is required"));
     }
-    if !has_method {
+    let Some(method) = method else {
         return Err(value.invalid("method field is required"));
+    };
+
+    Ok(JsonRpcRequest { id, method })
+}
+
+/// Token bucket limiting the throughput of some resource (e.g. bytes) to `rate` units per second.
+///
+/// This is approximate: a single large acquisition is allowed to overdraw the bucket,
+/// and the following acquisitions are delayed until the debt is paid back.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last_refill: std::time::Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: std::num::NonZeroU64) -> Self {
+        Self {
+            rate: rate.get() as f64,
+            tokens: rate.get() as f64, // Allows a burst of up to one second
+            last_refill: std::time::Instant::now(),
+        }
+    }
+
+    /// Blocks until `amount` units can be consumed.
+    pub fn acquire(&mut self, amount: usize) {
+        let now = std::time::Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+
+        self.tokens -= amount as f64;
+        if self.tokens < 0.0 {
+            std::thread::sleep(std::time::Duration::from_secs_f64(-self.tokens / self.rate));
+        }
+    }
+}
+
+static INTERRUPTED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Makes the first SIGINT (Ctrl-C) only set the flag observed by [`is_interrupted()`];
+/// the second one terminates the process immediately with status 130.
+///
+/// The handler is installed without `SA_RESTART`, so that a blocking read (e.g. of stdin)
+/// fails with `EINTR` instead of swallowing the first Ctrl-C (see [`InterruptibleReader`]).
+// On Linux, only the architectures whose `struct sigaction` matches the one below are allowed
+#[cfg(any(
+    all(
+        target_os = "linux",
+        any(
+            target_arch = "x86",
+            target_arch = "x86_64",
+            target_arch = "arm",
+            target_arch = "aarch64",
+            target_arch = "riscv32",
+            target_arch = "riscv64"
+        )
+    ),
+    target_os = "macos"
+))]
+pub fn install_interrupt_handler() {
+    const SIGINT: std::ffi::c_int = 2;
+
+    // `struct sigaction` of glibc / musl (`sigset_t` is 1024 bits of `unsigned long`)
+    #[cfg(target_os = "linux")]
+    #[repr(C)]
+    struct SigAction {
+        sa_handler: usize,
+        sa_mask: [std::ffi::c_ulong; 1024 / std::ffi::c_ulong::BITS as usize],
+        sa_flags: std::ffi::c_int,
+        sa_restorer: usize,
+    }
+
+    #[cfg(target_os = "macos")]
+    #[repr(C)]
+    struct SigAction {
+        sa_handler: usize,
+        sa_mask: u32,
+        sa_flags: std::ffi::c_int,
+    }
+
+    unsafe extern "C" {
+        fn sigaction(
+            signum: std::ffi::c_int,
+            act: *const SigAction,
+            oldact: *mut SigAction,
+        ) -> std::ffi::c_int;
+        fn _exit(status: std::ffi::c_int) -> !;
+    }
+
+    extern "C" fn handle_sigint(_signum: std::ffi::c_int) {
+        if INTERRUPTED.swap(true, std::sync::atomic::Ordering::SeqCst) {
+            // SAFETY: `_exit()` is async-signal-safe
+            unsafe { _exit(130) };
+        }
     }
 
-    Ok(id)
+    // SAFETY: an all-zero `struct sigaction` is valid (empty mask and no flags)
+    let mut action: SigAction = unsafe { std::mem::zeroed() };
+    action.sa_handler = handle_sigint as extern "C" fn(std::ffi::c_int) as usize;
+
+    // SAFETY: the handler only touches an atomic and calls an async-signal-safe function
+    unsafe { sigaction(SIGINT, &action, std::ptr::null_mut()) };
+}
+
+/// Interrupts are not intercepted on this platform.
+#[cfg(not(any(
+    all(
+        target_os = "linux",
+        any(
+            target_arch = "x86",
+            target_arch = "x86_64",
+            target_arch = "arm",
+            target_arch = "aarch64",
+            target_arch = "riscv32",
+            target_arch = "riscv64"
+        )
+    ),
+    target_os = "macos"
+)))]
+pub fn install_interrupt_handler() {}
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(std::sync::atomic::Ordering::SeqCst)
+}
+
+/// Reader that reports EOF once a read is interrupted by Ctrl-C.
+///
+/// `BufRead::read_line()` retries on `EINTR`, so without this wrapper a blocking read would
+/// only notice [`is_interrupted()`] after the next line arrives.
+#[derive(Debug)]
+pub struct InterruptibleReader<R>(pub R);
+
+impl<R: std::io::Read> std::io::Read for InterruptibleReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if is_interrupted() {
+            return Ok(0);
+        }
+        match self.0.read(buf) {
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted && is_interrupted() => Ok(0),
+            result => result,
+        }
+    }
+}
+
+impl<R: std::io::BufRead> std::io::BufRead for InterruptibleReader<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if is_interrupted() {
+            return Ok(&[]);
+        }
+        match self.0.fill_buf() {
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted && is_interrupted() => Ok(&[]),
+            result => result,
+        }
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.0.consume(amount);
+    }
+}
+
+/// Restricts `socket` to the network interface `device` (`SO_BINDTODEVICE`).
+// Only the architectures using the `asm-generic` values below are allowed
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "riscv32",
+        target_arch = "riscv64"
+    )
+))]
+pub fn bind_to_device(socket: &std::net::UdpSocket, device: &str) -> crate::Result<()> {
+    // The values shared by most architectures (see `asm-generic/socket.h`)
+    const SOL_SOCKET: std::ffi::c_int = 1;
+    const SO_BINDTODEVICE: std::ffi::c_int = 25;
+
+    set_socket_option(socket, SOL_SOCKET, SO_BINDTODEVICE, device.as_bytes())
+        .map_err(|e| crate::Error::new(format!("failed to bind socket to device {device:?}: {e}")))
+}
+
+/// Restricts `socket` to the network interface `device` (not supported on this platform).
+#[cfg(not(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "riscv32",
+        target_arch = "riscv64"
+    )
+)))]
+pub fn bind_to_device(_socket: &std::net::UdpSocket, device: &str) -> crate::Result<()> {
+    Err(crate::Error::new(format!(
+        "cannot bind socket to device {device:?}: --bind-device is not supported on this platform"
+    )))
+}
+
+/// Sets the don't-fragment bit on outgoing packets (`IP_MTU_DISCOVER` / `IPV6_MTU_DISCOVER`),
+/// so that packets exceeding the path MTU fail instead of being fragmented.
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "riscv32",
+        target_arch = "riscv64"
+    )
+))]
+pub fn set_dont_fragment(socket: &std::net::UdpSocket) -> crate::Result<()> {
+    const IPPROTO_IP: std::ffi::c_int = 0;
+    const IP_MTU_DISCOVER: std::ffi::c_int = 10;
+    const IPPROTO_IPV6: std::ffi::c_int = 41;
+    const IPV6_MTU_DISCOVER: std::ffi::c_int = 23;
+    const PMTUDISC_DO: std::ffi::c_int = 2;
+
+    let (level, name) = if socket.local_addr()?.is_ipv6() {
+        (IPPROTO_IPV6, IPV6_MTU_DISCOVER)
+    } else {
+        (IPPROTO_IP, IP_MTU_DISCOVER)
+    };
+    set_socket_option(socket, level, name, &PMTUDISC_DO.to_ne_bytes())
+        .map_err(|e| crate::Error::new(format!("failed to set the don't-fragment bit: {e}")))
+}
+
+/// Sets the don't-fragment bit on outgoing packets (not supported on this platform).
+#[cfg(not(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "riscv32",
+        target_arch = "riscv64"
+    )
+)))]
+pub fn set_dont_fragment(_socket: &std::net::UdpSocket) -> crate::Result<()> {
+    Err(crate::Error::new(
+        "setting the don't-fragment bit is not supported on this platform",
+    ))
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "riscv32",
+        target_arch = "riscv64"
+    )
+))]
+fn set_socket_option(
+    socket: &std::net::UdpSocket,
+    level: std::ffi::c_int,
+    name: std::ffi::c_int,
+    value: &[u8],
+) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    unsafe extern "C" {
+        fn setsockopt(
+            socket: std::ffi::c_int,
+            level: std::ffi::c_int,
+            name: std::ffi::c_int,
+            value: *const std::ffi::c_void,
+            len: u32,
+        ) -> std::ffi::c_int;
+    }
+
+    let len = u32::try_from(value.len()).map_err(|_| std::io::ErrorKind::InvalidInput)?;
+    // SAFETY: `value` points to `len` readable bytes that outlive the call
+    let ret = unsafe { setsockopt(socket.as_raw_fd(), level, name, value.as_ptr().cast(), len) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Resolves a JSON Pointer (RFC 6901) such as `/result/items/0` against `value`.
+pub fn resolve_json_pointer<'text, 'raw>(
+    value: nojson::RawJsonValue<'text, 'raw>,
+    pointer: &str,
+) -> Option<nojson::RawJsonValue<'text, 'raw>> {
+    if pointer.is_empty() {
+        return Some(value);
+    }
+    let mut current = value;
+    for token in pointer.strip_prefix('/')?.split('/') {
+        let token = token.replace("~1", "/").replace("~0", "~");
+        current = match current.kind() {
+            nojson::JsonValueKind::Object => current
+                .to_object()
+                .ok()?
+                .find(|(name, _)| name.to_unquoted_string_str().is_ok_and(|n| n == token))
+                .map(|(_, v)| v)?,
+            nojson::JsonValueKind::Array => {
+                let index: usize = token.parse().ok()?;
+                current.to_array().ok()?.nth(index)?
+            }
+            _ => return None,
+        };
+    }
+    Some(current)
+}
+
+/// Returns `value` as JSON text with the location of a JSON Pointer set to `replacement`.
+///
+/// Missing object members (including intermediate ones) are created, and `-` or an index
+/// equal to the length of an array appends a new element.
+pub fn set_json_pointer(
+    value: nojson::RawJsonValue<'_, '_>,
+    pointer: &str,
+    replacement: nojson::RawJsonValue<'_, '_>,
+) -> Result<String, String> {
+    let tokens = match pointer {
+        "" => Vec::new(),
+        _ => pointer
+            .strip_prefix('/')
+            .ok_or_else(|| format!("JSON Pointer {pointer:?} must start with '/'"))?
+            .split('/')
+            .map(|token| token.replace("~1", "/").replace("~0", "~"))
+            .collect(),
+    };
+
+    // Array indices are checked beforehand as formatters cannot report the reason of errors
+    let mut current = Some(value);
+    for token in &tokens {
+        current = match current {
+            Some(v) if v.kind() == nojson::JsonValueKind::Array => {
+                let len = v.to_array().map_err(|e| e.to_string())?.count();
+                let index = match token.as_str() {
+                    "-" => len,
+                    _ => token
+                        .parse()
+                        .map_err(|_| format!("invalid array index {token:?} in {pointer:?}"))?,
+                };
+                if index > len {
+                    return Err(format!(
+                        "array index {index} is out of range in {pointer:?}"
+                    ));
+                }
+                v.to_array()
+                    .ok()
+                    .and_then(|mut elements| elements.nth(index))
+            }
+            Some(v) if v.kind() == nojson::JsonValueKind::Object => v
+                .to_object()
+                .ok()
+                .and_then(|mut members| {
+                    members
+                        .find(|(name, _)| name.to_unquoted_string_str().is_ok_and(|n| n == *token))
+                })
+                .map(|(_, v)| v),
+            _ => None,
+        };
+    }
+
+    let replaced = PointerReplacement {
+        value: Some(value),
+        tokens: &tokens,
+        replacement,
+    };
+    Ok(nojson::json(|f| f.value(&replaced)).to_string())
+}
+
+// Formats `value` with the location of `tokens` replaced (missing locations are created)
+struct PointerReplacement<'a, 't, 'r> {
+    value: Option<nojson::RawJsonValue<'t, 'r>>,
+    tokens: &'a [String],
+    replacement: nojson::RawJsonValue<'t, 'r>,
+}
+
+impl nojson::DisplayJson for PointerReplacement<'_, '_, '_> {
+    fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
+        let Some((token, rest)) = self.tokens.split_first() else {
+            return f.value(self.replacement);
+        };
+        let child = |value| PointerReplacement {
+            value,
+            tokens: rest,
+            replacement: self.replacement,
+        };
+        match self.value {
+            Some(value) if value.kind() == nojson::JsonValueKind::Array => {
+                let elements: Vec<_> = value.to_array().map_err(|_| std::fmt::Error)?.collect();
+                let index = token.parse().unwrap_or(elements.len()); // `-` appends
+                f.array(|f| {
+                    for (i, element) in elements.iter().enumerate() {
+                        if i == index {
+                            f.element(child(Some(*element)))?;
+                        } else {
+                            f.element(element)?;
+                        }
+                    }
+                    if index >= elements.len() {
+                        f.element(child(None))?;
+                    }
+                    Ok(())
+                })
+            }
+            Some(value) if value.kind() == nojson::JsonValueKind::Object => {
+                let mut found = false;
+                f.object(|f| {
+                    for (name, member) in value.to_object().map_err(|_| std::fmt::Error)? {
+                        let name = name.to_unquoted_string_str().map_err(|_| std::fmt::Error)?;
+                        if name == token.as_str() {
+                            found = true;
+                            f.member(name, child(Some(member)))?;
+                        } else {
+                            f.member(name, member)?;
+                        }
+                    }
+                    if !found {
+                        f.member(token, child(None))?;
+                    }
+                    Ok(())
+                })
+            }
+            // Missing (or scalar) locations are replaced with objects
+            _ => f.object(|f| f.member(token, child(None))),
+        }
+    }
+}
+
+/// Compares two JSON values structurally (object member order and number notation are ignored).
+pub fn json_values_equal(a: nojson::RawJsonValue<'_, '_>, b: nojson::RawJsonValue<'_, '_>) -> bool {
+    use nojson::JsonValueKind::*;
+    match (a.kind(), b.kind()) {
+        (Null, Null) => true,
+        (Boolean, Boolean) => a.as_raw_str() == b.as_raw_str(),
+        (Integer | Float, Integer | Float) => {
+            let parse = |v: nojson::RawJsonValue<'_, '_>| v.as_raw_str().parse::<f64>().ok();
+            parse(a).is_some_and(|x| Some(x) == parse(b))
+        }
+        (String, String) => a.to_unquoted_string_str().ok() == b.to_unquoted_string_str().ok(),
+        (Array, Array) => {
+            let (Ok(xs), Ok(ys)) = (a.to_array(), b.to_array()) else {
+                return false;
+            };
+            let (xs, ys): (Vec<_>, Vec<_>) = (xs.collect(), ys.collect());
+            xs.len() == ys.len() && xs.into_iter().zip(ys).all(|(x, y)| json_values_equal(x, y))
+        }
+        (Object, Object) => {
+            let (Ok(xs), Ok(ys)) = (a.to_object(), b.to_object()) else {
+                return false;
+            };
+            let (xs, ys): (Vec<_>, Vec<_>) = (xs.collect(), ys.collect());
+            xs.len() == ys.len()
+                && xs.iter().all(|(xk, xv)| {
+                    ys.iter().any(|(yk, yv)| {
+                        xk.to_unquoted_string_str().ok() == yk.to_unquoted_string_str().ok()
+                            && json_values_equal(*xv, *yv)
+                    })
+                })
+        }
+        _ => false,
+    }
+}
+
+/// Small, seedable pseudo random number generator (SplitMix64).
+///
+/// This is not cryptographically secure; it is only intended for generating reproducible test traffic.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    /// Creates a generator seeded from the current time.
+    pub fn from_time() -> Self {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Self::new(now.as_nanos() as u64)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a value in the range `[0.0, 1.0)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Processes backslash escapes (`\n`, `\r`, `\t`, `\0`, `\\` and `\xHH`) in `s`.
+pub fn unescape_bytes(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some('0') => bytes.push(0),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                // `from_str_radix()` alone would also accept a sign (e.g. "\x+1")
+                let b = Some(&hex)
+                    .filter(|hex| hex.len() == 2 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| format!("invalid escape sequence \"\\x{hex}\""))?;
+                bytes.push(b);
+            }
+            Some(c) => return Err(format!("unknown escape sequence \"\\{c}\"")),
+            None => return Err("trailing backslash".to_owned()),
+        }
+    }
+    Ok(bytes)
+}
+
+/// Formats a JSON value with the members of every (nested) object sorted by name.
+#[derive(Debug, Clone, Copy)]
+pub struct SortedKeys<'text, 'raw>(pub nojson::RawJsonValue<'text, 'raw>);
+
+impl nojson::DisplayJson for SortedKeys<'_, '_> {
+    fn fmt(&self, f: &mut nojson::JsonFormatter<'_, '_>) -> std::fmt::Result {
+        match self.0.kind() {
+            nojson::JsonValueKind::Object => {
+                let mut members = self
+                    .0
+                    .to_object()
+                    .map_err(|_| std::fmt::Error)?
+                    .map(|(name, value)| Ok((name.to_unquoted_string_str()?, value)))
+                    .collect::<Result<Vec<_>, nojson::JsonParseError>>()
+                    .map_err(|_| std::fmt::Error)?;
+                members.sort_by(|a, b| a.0.cmp(&b.0));
+                f.object(|f| f.members(members.into_iter().map(|(n, v)| (n, SortedKeys(v)))))
+            }
+            nojson::JsonValueKind::Array => {
+                let elements = self.0.to_array().map_err(|_| std::fmt::Error)?;
+                f.array(|f| f.elements(elements.map(SortedKeys)))
+            }
+            _ => f.value(self.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_secs_rejects_invalid_values() {
+        assert_eq!(
+            parse_duration_secs("1.5"),
+            Ok(std::time::Duration::from_millis(1500))
+        );
+        assert!(parse_duration_secs("-1").is_err());
+        assert!(parse_duration_secs("NaN").is_err());
+        assert!(parse_duration_secs("1e30").is_err());
+        assert!(parse_duration_secs("abc").is_err());
+    }
+
+    #[test]
+    fn read_line_with_limit_checks_each_line() {
+        let mut reader = "abc\r\ndefg\n".as_bytes();
+        let mut buf = String::new();
+        assert_eq!(read_line_with_limit(&mut reader, &mut buf, 3).ok(), Some(5));
+        assert_eq!(buf, "abc\r\n");
+
+        // The previous line in `buf` does not count towards the limit
+        let mut reader = "defg\n".as_bytes();
+        assert_eq!(read_line_with_limit(&mut reader, &mut buf, 4).ok(), Some(5));
+        assert_eq!(buf, "abc\r\ndefg\n");
+
+        let mut reader = "defg\n".as_bytes();
+        let e = read_line_with_limit(&mut reader, &mut String::new(), 3).expect_err("too long");
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_line_with_limit_accepts_last_line_without_terminator() {
+        let mut reader = "abc".as_bytes();
+        let mut buf = String::new();
+        assert_eq!(read_line_with_limit(&mut reader, &mut buf, 3).ok(), Some(3));
+        assert_eq!(read_line_with_limit(&mut reader, &mut buf, 3).ok(), Some(0));
+        assert_eq!(buf, "abc");
+    }
+
+    fn set(value: &str, pointer: &str, replacement: &str) -> Result<String, String> {
+        let value = nojson::RawJson::parse(value).map_err(|e| e.to_string())?;
+        let replacement = nojson::RawJson::parse(replacement).map_err(|e| e.to_string())?;
+        set_json_pointer(value.value(), pointer, replacement.value())
+    }
+
+    #[test]
+    fn set_json_pointer_replaces_and_creates_locations() {
+        let value = r#"{"a": {"b": [1, 2]}, "c/d": 3, "e~f": 4}"#;
+        assert_eq!(set(value, "", "null"), Ok("null".to_owned()));
+        assert_eq!(
+            set(value, "/a/b/1", r#""x""#),
+            Ok(r#"{"a":{"b":[1,"x"]},"c/d":3,"e~f":4}"#.to_owned())
+        );
+        assert_eq!(
+            set(value, "/a/b/-", "3"),
+            Ok(r#"{"a":{"b":[1,2,3]},"c/d":3,"e~f":4}"#.to_owned())
+        );
+        assert_eq!(
+            set(value, "/a/b/2", "3"),
+            Ok(r#"{"a":{"b":[1,2,3]},"c/d":3,"e~f":4}"#.to_owned())
+        );
+        assert_eq!(
+            set(value, "/c~1d", "true"),
+            Ok(r#"{"a":{"b":[1,2]},"c/d":true,"e~f":4}"#.to_owned())
+        );
+        assert_eq!(
+            set(value, "/e~0f", "true"),
+            Ok(r#"{"a":{"b":[1,2]},"c/d":3,"e~f":true}"#.to_owned())
+        );
+        assert_eq!(set("{}", "/x/y", "1"), Ok(r#"{"x":{"y":1}}"#.to_owned()));
+    }
+
+    #[test]
+    fn set_json_pointer_rejects_invalid_pointers() {
+        let value = r#"{"a": [1, 2]}"#;
+        assert!(set(value, "a", "1").is_err());
+        assert!(set(value, "/a/3", "1").is_err());
+        assert!(set(value, "/a/x", "1").is_err());
+    }
+
+    #[test]
+    fn unescape_bytes_processes_escapes() {
+        assert_eq!(
+            unescape_bytes(r"a\n\r\t\0\\\x7f\xFFé"),
+            Ok(b"a\n\r\t\0\\\x7f\xff\xc3\xa9".to_vec())
+        );
+        assert_eq!(unescape_bytes(""), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn unescape_bytes_rejects_invalid_escapes() {
+        assert_eq!(
+            unescape_bytes(r"\q"),
+            Err(r#"unknown escape sequence "\q""#.to_owned())
+        );
+        assert_eq!(unescape_bytes("a\\"), Err("trailing backslash".to_owned()));
+        assert_eq!(
+            unescape_bytes(r"\x4"),
+            Err(r#"invalid escape sequence "\x4""#.to_owned())
+        );
+        assert!(unescape_bytes(r"\xg0").is_err());
+        assert!(unescape_bytes(r"\x+1").is_err());
+    }
 }
@@ -0,0 +1,164 @@
+/// Level and format of diagnostic messages written to stderr.
+///
+/// The level is increased by each occurrence of the global `--verbose` flag (e.g., `-vv` means
+/// [`Verbosity::DEBUG`]), or set directly by the global `--log-level` option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Verbosity {
+    level: u8,
+    format: LogFormat,
+}
+
+impl Default for Verbosity {
+    fn default() -> Self {
+        Self::WARN
+    }
+}
+
+impl Verbosity {
+    /// Level printing no diagnostics (errors are still reported on exit).
+    pub const ERROR: Self = Self::with_level(0);
+
+    /// Default level, printing only warnings.
+    pub const WARN: Self = Self::with_level(1);
+
+    /// Level for general diagnostics (e.g. resolved addresses and packet packing).
+    pub const INFO: Self = Self::with_level(2);
+
+    /// Level for detailed diagnostics (e.g. per-request timing).
+    pub const DEBUG: Self = Self::with_level(3);
+
+    const fn with_level(level: u8) -> Self {
+        Self {
+            level,
+            format: LogFormat::Text,
+        }
+    }
+
+    pub fn take(args: &mut noargs::RawArgs) -> noargs::Result<Self> {
+        let mut verbose = 0u8;
+        while noargs::flag("verbose")
+            .short('v')
+            .doc("Print diagnostic messages to stderr (repeat for more detail)")
+            .take(args)
+            .is_present()
+        {
+            verbose = verbose.saturating_add(1);
+        }
+        let level: Option<Self> = noargs::opt("log-level")
+            .ty("error | warn | info | debug")
+            .doc("Level of diagnostic messages printed to stderr (default: warn; see also --verbose)")
+            .take(args)
+            .present_and_then(|o| o.value().parse())?;
+        let format: LogFormat = noargs::opt("log-format")
+            .ty("text | json")
+            .doc("Format of diagnostic messages (`json` prints {\"timestamp\",\"level\",\"message\"} lines)")
+            .default("text")
+            .take(args)
+            .then(|o| o.value().parse())?;
+
+        if level.is_some() && verbose > 0 {
+            return Err(noargs::Error::other(
+                args,
+                "--log-level and --verbose cannot be specified together",
+            ));
+        }
+        let level = level.map_or(Self::WARN.level.saturating_add(verbose), |v| v.level);
+        Ok(Self { level, format })
+    }
+
+    pub fn is_enabled(self, level: Self) -> bool {
+        self.level >= level.level
+    }
+
+    /// Returns whether `--log-format json` is specified.
+    pub fn is_json(self) -> bool {
+        self.format == LogFormat::Json
+    }
+
+    /// Prints `message` regardless of the level.
+    pub fn error<T: std::fmt::Display>(self, message: T) {
+        self.log(Self::ERROR, message);
+    }
+
+    pub fn warn<T: std::fmt::Display>(self, message: T) {
+        self.log(Self::WARN, message);
+    }
+
+    pub fn info<T: std::fmt::Display>(self, message: T) {
+        self.log(Self::INFO, message);
+    }
+
+    pub fn debug<T: std::fmt::Display>(self, message: T) {
+        self.log(Self::DEBUG, message);
+    }
+
+    fn log<T: std::fmt::Display>(self, level: Self, message: T) {
+        if !self.is_enabled(level) {
+            return;
+        }
+        let name = match level.level {
+            0 => "error",
+            1 => "warn",
+            2 => "info",
+            _ => "debug",
+        };
+        match self.format {
+            LogFormat::Text if name == "error" => eprintln!("[jlou] error: {message}"),
+            LogFormat::Text if name == "warn" => eprintln!("[jlou] warning: {message}"),
+            LogFormat::Text => eprintln!("[jlou] {message}"),
+            LogFormat::Json => {
+                let timestamp = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs_f64();
+                let line = nojson::object(|f| {
+                    f.member("timestamp", timestamp)?;
+                    f.member("level", name)?;
+                    f.member("message", message.to_string())
+                });
+                eprintln!("{line}");
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for Verbosity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Self::ERROR),
+            "warn" => Ok(Self::WARN),
+            "info" => Ok(Self::INFO),
+            "debug" => Ok(Self::DEBUG),
+            _ => Err(format!(
+                "unknown log level {s:?} (expected \"error\", \"warn\", \"info\" or \"debug\")"
+            )),
+        }
+    }
+}
+
+/// Format of diagnostic messages written to stderr.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LogFormat {
+    /// `[jlou] MESSAGE` lines.
+    #[default]
+    Text,
+
+    /// `{"timestamp":SECONDS,"level":LEVEL,"message":MESSAGE}` lines.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "unknown log format {s:?} (expected \"text\" or \"json\")"
+            )),
+        }
+    }
+}